@@ -0,0 +1,55 @@
+//! Times the interpreter on the scripts in this directory. Run once with and once without the
+//! `nan_boxing` feature to compare the two value representations:
+//!
+//! ```sh
+//! cargo bench
+//! cargo bench --features nan_boxing
+//! ```
+
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+const RUNS: usize = 5;
+
+fn time_script(path: &Path) -> Duration {
+    let mut times: Vec<_> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let output = Command::new(env!("CARGO_BIN_EXE_old_iron_lox"))
+                .arg(path)
+                .output()
+                .unwrap();
+            let elapsed = start.elapsed();
+            assert!(output.status.success(), "{} failed", path.display());
+            elapsed
+        })
+        .collect();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    let representation = if cfg!(feature = "nan_boxing") {
+        "nan boxed"
+    } else {
+        "enum"
+    };
+    println!("value representation: {representation}, median of {RUNS} runs");
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        println!("{name:<12} {:>8.2?}", time_script(&path));
+    }
+}
@@ -1,11 +1,14 @@
 use std::ptr::null_mut;
 
 use crate::{
+    diagnostic::Span,
     memory::{free_array, grow_array, grow_capacity},
     value::{Value, ValueArray},
+    vm::Vm,
 };
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 pub enum OpCode {
     Constant = 0,
     Nil = 1,
@@ -21,6 +24,44 @@ pub enum OpCode {
     Not = 11,
     Negate = 12,
     Return = 13,
+    Print = 14,
+    Pop = 15,
+    DefineGlobal = 16,
+    GetGlobal = 17,
+    SetGlobal = 18,
+    GetLocal = 19,
+    SetLocal = 20,
+    Jump = 21,
+    JumpIfFalse = 22,
+    Loop = 23,
+    Call = 24,
+    Closure = 25,
+    GetUpvalue = 26,
+    SetUpvalue = 27,
+    CloseUpvalue = 28,
+    Class = 29,
+    GetProperty = 30,
+    SetProperty = 31,
+    Method = 32,
+    Invoke = 33,
+    Inherit = 34,
+    GetSuper = 35,
+    SuperInvoke = 36,
+    /// Like `Constant`, but with a 24 bit big endian operand for chunks with more than 256
+    /// constants.
+    ConstantLong = 37,
+    // long forms of the other instructions with a constant operand, see `OpCode::long`
+    DefineGlobalLong = 38,
+    GetGlobalLong = 39,
+    SetGlobalLong = 40,
+    ClosureLong = 41,
+    ClassLong = 42,
+    GetPropertyLong = 43,
+    SetPropertyLong = 44,
+    MethodLong = 45,
+    InvokeLong = 46,
+    GetSuperLong = 47,
+    SuperInvokeLong = 48,
 }
 
 impl From<u8> for OpCode {
@@ -41,11 +82,87 @@ impl From<u8> for OpCode {
             11 => OpCode::Not,
             12 => OpCode::Negate,
             13 => OpCode::Return,
+            14 => OpCode::Print,
+            15 => OpCode::Pop,
+            16 => OpCode::DefineGlobal,
+            17 => OpCode::GetGlobal,
+            18 => OpCode::SetGlobal,
+            19 => OpCode::GetLocal,
+            20 => OpCode::SetLocal,
+            21 => OpCode::Jump,
+            22 => OpCode::JumpIfFalse,
+            23 => OpCode::Loop,
+            24 => OpCode::Call,
+            25 => OpCode::Closure,
+            26 => OpCode::GetUpvalue,
+            27 => OpCode::SetUpvalue,
+            28 => OpCode::CloseUpvalue,
+            29 => OpCode::Class,
+            30 => OpCode::GetProperty,
+            31 => OpCode::SetProperty,
+            32 => OpCode::Method,
+            33 => OpCode::Invoke,
+            34 => OpCode::Inherit,
+            35 => OpCode::GetSuper,
+            36 => OpCode::SuperInvoke,
+            37 => OpCode::ConstantLong,
+            38 => OpCode::DefineGlobalLong,
+            39 => OpCode::GetGlobalLong,
+            40 => OpCode::SetGlobalLong,
+            41 => OpCode::ClosureLong,
+            42 => OpCode::ClassLong,
+            43 => OpCode::GetPropertyLong,
+            44 => OpCode::SetPropertyLong,
+            45 => OpCode::MethodLong,
+            46 => OpCode::InvokeLong,
+            47 => OpCode::GetSuperLong,
+            48 => OpCode::SuperInvokeLong,
             _ => panic!("unexpected value {value} for OpCode"),
         }
     }
 }
 
+impl OpCode {
+    /// The form of an instruction that takes a 24 bit big endian constant index instead of a one
+    /// byte operand, for chunks with more than 256 constants.
+    pub fn long(self) -> OpCode {
+        match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            _ => panic!("instruction doesn't have a constant operand"),
+        }
+    }
+
+    /// Whether the instruction's constant operand is 24 bits rather than a byte.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::InvokeLong
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvokeLong
+        )
+    }
+}
+
 // it'd probably be better to use a `Vec` or `Bytes`, but we use some unsafe here
 // for learning purposes.
 pub struct Chunk {
@@ -53,9 +170,29 @@ pub struct Chunk {
     pub(crate) capacity: usize,
     // array of op codes
     pub(crate) code: *mut u8,
-    // array of line that above code corresponds to
-    pub(crate) lines: *mut usize,
     pub(crate) constants: ValueArray,
+    pub(crate) line_count: usize,
+    pub(crate) line_capacity: usize,
+    /// run length encoded line numbers, one entry for each run of bytes from the same line
+    pub(crate) lines: *mut LineStart,
+    pub(crate) span_count: usize,
+    pub(crate) span_capacity: usize,
+    /// run length encoded source spans, so errors can point at the code an instruction came from
+    pub(crate) spans: *mut SpanStart,
+}
+
+/// The first byte of a run of code that all came from the same source line.
+#[derive(Clone, Copy)]
+pub struct LineStart {
+    pub(crate) offset: usize,
+    pub(crate) line: usize,
+}
+
+/// The first byte of a run of code that all came from the same span of source.
+#[derive(Clone, Copy)]
+pub struct SpanStart {
+    pub(crate) offset: usize,
+    pub(crate) span: Span,
 }
 
 impl Chunk {
@@ -64,42 +201,163 @@ impl Chunk {
             count: 0,
             capacity: 0,
             code: null_mut(),
-            lines: null_mut(),
             constants: ValueArray::new(),
+            line_count: 0,
+            line_capacity: 0,
+            lines: null_mut(),
+            span_count: 0,
+            span_capacity: 0,
+            spans: null_mut(),
         }
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
+    pub fn write_chunk(&mut self, vm: &mut Vm, byte: u8, line: usize, span: Span) {
         if self.capacity < self.count + 1 {
             let old_capacity = self.capacity;
             self.capacity = grow_capacity(old_capacity);
             // Safety:
             // - always allocated from calls to grow_array
             // - layout is always u8
-            self.code = unsafe { grow_array::<u8>(self.code, old_capacity, self.capacity) };
-            self.lines = unsafe { grow_array::<usize>(self.lines, old_capacity, self.capacity) }
+            self.code = unsafe { grow_array::<u8>(vm, self.code, old_capacity, self.capacity) };
         }
 
         // Safety:
         // - We checked that we have enough allocation above.
         // - u8 is always aligned
         unsafe { *self.code.add(self.count) = byte };
-        unsafe { *self.lines.add(self.count) = line };
         self.count += 1;
+        let offset = self.count - 1;
+
+        self.write_span(vm, offset, span);
+
+        // still on the same line, so the current run covers this byte too
+        if self.line_count > 0 && unsafe { (*self.lines.add(self.line_count - 1)).line } == line {
+            return;
+        }
+
+        if self.line_capacity < self.line_count + 1 {
+            let old_capacity = self.line_capacity;
+            self.line_capacity = grow_capacity(old_capacity);
+            self.lines = unsafe {
+                grow_array::<LineStart>(vm, self.lines, old_capacity, self.line_capacity)
+            };
+        }
+
+        unsafe { *self.lines.add(self.line_count) = LineStart { offset, line } };
+        self.line_count += 1;
     }
 
-    pub fn free_chunk(&mut self) {
+    fn write_span(&mut self, vm: &mut Vm, offset: usize, span: Span) {
+        // the bytes of an instruction share its span, so they end up in the same run
+        if self.span_count > 0 && unsafe { (*self.spans.add(self.span_count - 1)).span } == span {
+            return;
+        }
+
+        if self.span_capacity < self.span_count + 1 {
+            let old_capacity = self.span_capacity;
+            self.span_capacity = grow_capacity(old_capacity);
+            self.spans = unsafe {
+                grow_array::<SpanStart>(vm, self.spans, old_capacity, self.span_capacity)
+            };
+        }
+
+        unsafe { *self.spans.add(self.span_count) = SpanStart { offset, span } };
+        self.span_count += 1;
+    }
+
+    /// Source line of the instruction at `offset`.
+    pub fn get_line(&self, offset: usize) -> usize {
+        if self.line_count == 0 {
+            return 0;
+        }
+        let lines = unsafe { std::slice::from_raw_parts(self.lines, self.line_count) };
+        // the run containing offset is the last one starting at or before it
+        let run = lines.partition_point(|start| start.offset <= offset);
+        lines[run.saturating_sub(1)].line
+    }
+
+    /// Source the instruction at `offset` was compiled from.
+    pub fn get_span(&self, offset: usize) -> Span {
+        if self.span_count == 0 {
+            return Span { start: 0, end: 0 };
+        }
+        let spans = unsafe { std::slice::from_raw_parts(self.spans, self.span_count) };
+        let run = spans.partition_point(|start| start.offset <= offset);
+        spans[run.saturating_sub(1)].span
+    }
+
+    pub fn free_chunk(&mut self, vm: &mut Vm) {
         // Safety:
         // - always allocated from calls to grow_array
         // - layout is always u8
-        unsafe { free_array::<u8>(self.code, self.capacity) };
-        unsafe { free_array::<usize>(self.lines, self.capacity) };
-        self.constants.free_value_array();
+        unsafe { free_array::<u8>(vm, self.code, self.capacity) };
+        unsafe { free_array::<LineStart>(vm, self.lines, self.line_capacity) };
+        unsafe { free_array::<SpanStart>(vm, self.spans, self.span_capacity) };
+        self.constants.free_value_array(vm);
         *self = Self::new();
     }
 
-    pub fn add_constant(&mut self, value: Value) -> usize {
-        self.constants.write_value_array(value);
+    pub fn add_constant(&mut self, vm: &mut Vm, value: Value) -> usize {
+        // growing the constants can trigger a collection, so keep the value reachable
+        vm.push(value);
+        self.constants.write_value_array(vm, value);
+        vm.pop();
         self.constants.count - 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, OpCode};
+    use crate::{diagnostic::Span, vm::Vm};
+
+    const SPAN: Span = Span { start: 0, end: 0 };
+
+    #[test]
+    fn lines_are_run_length_encoded() {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        for (byte, line) in [(0, 1), (1, 1), (2, 1), (3, 2), (4, 4), (5, 4)] {
+            chunk.write_chunk(&mut vm, byte, line, SPAN);
+        }
+
+        assert_eq!(chunk.line_count, 3);
+        let lines: Vec<_> = (0..chunk.count)
+            .map(|offset| chunk.get_line(offset))
+            .collect();
+        assert_eq!(lines, [1, 1, 1, 2, 4, 4]);
+        chunk.free_chunk(&mut vm);
+    }
+
+    #[test]
+    fn returning_to_a_line_starts_a_new_run() {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        chunk.write_chunk(&mut vm, OpCode::Nil as u8, 3, SPAN);
+        chunk.write_chunk(&mut vm, OpCode::Pop as u8, 5, SPAN);
+        chunk.write_chunk(&mut vm, OpCode::Return as u8, 3, SPAN);
+
+        assert_eq!(chunk.line_count, 3);
+        assert_eq!(chunk.get_line(0), 3);
+        assert_eq!(chunk.get_line(1), 5);
+        assert_eq!(chunk.get_line(2), 3);
+        chunk.free_chunk(&mut vm);
+    }
+
+    #[test]
+    fn instructions_map_back_to_their_spans() {
+        let mut vm = Vm::new();
+        let mut chunk = Chunk::new();
+        let name = Span { start: 4, end: 7 };
+        let plus = Span { start: 10, end: 11 };
+        chunk.write_chunk(&mut vm, OpCode::GetGlobal as u8, 1, name);
+        chunk.write_chunk(&mut vm, 0, 1, name);
+        chunk.write_chunk(&mut vm, OpCode::Add as u8, 1, plus);
+
+        assert_eq!(chunk.span_count, 2);
+        assert_eq!(chunk.get_span(0), name);
+        assert_eq!(chunk.get_span(1), name);
+        assert_eq!(chunk.get_span(2), plus);
+        chunk.free_chunk(&mut vm);
+    }
+}
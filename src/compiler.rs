@@ -4,43 +4,141 @@ use std::{fmt::Debug, ptr::null_mut};
 
 use crate::{
     chunk::{Chunk, OpCode},
+    diagnostic::{Diagnostic, Severity},
+    memory::mark_object,
+    object::{Obj, ObjFunction, copy_string, new_function},
     scanner::{
         Token,
         TokenType::{self, *},
-        scan,
+        escape, parse_number, scan,
     },
+    table::Table,
     value::Value,
+    vm::Vm,
 };
 
-static mut COMPILING_CHUNK: *mut Chunk = null_mut();
+const U8_COUNT: usize = u8::MAX as usize + 1;
+/// Largest constant index that fits in the 24 bit operand of `ConstantLong`.
+const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
 
-// NOTE: lifetime here is incorrect
-fn current_chunk() -> &'static mut Chunk {
-    unsafe { &mut *COMPILING_CHUNK }
+/// Functions being compiled aren't reachable from the vm yet, so they are roots.
+pub fn mark_compiler_roots(vm: &mut Vm) {
+    let mut compiler = vm.compiler;
+    while !compiler.is_null() {
+        mark_object(vm, unsafe { (*compiler).function } as *mut Obj);
+        compiler = unsafe { (*compiler).enclosing };
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Local {
+    name: Token,
+    /// None until the variable's initializer has been compiled
+    depth: Option<usize>,
+    /// captured by a closure, so it needs to be moved to the heap when it goes out of scope
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Upvalue {
+    /// local slot in the enclosing function if `is_local`, otherwise the index of the
+    /// enclosing function's upvalue
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+pub(crate) struct Compiler {
+    /// compiler of the function this one is nested in, null for the top level script
+    enclosing: *mut Compiler,
+    function: *mut ObjFunction,
+    typee: FunctionType,
+
+    /// locals in the order they appear on the stack
+    locals: [Local; U8_COUNT],
+    local_count: usize,
+    upvalues: [Upvalue; U8_COUNT],
+    /// 0 is global scope
+    scope_depth: usize,
+    /// index of the constant holding each name used in the function, so a name repeated many
+    /// times only takes up one constant
+    identifiers: Table,
 }
 
-pub fn compile(source: &str, chunk: &mut Chunk) -> bool {
-    // for token in std::iter::from_coroutine(scan(source)) {
-    unsafe {
-        COMPILING_CHUNK = chunk as *mut Chunk;
+/// Tracks the innermost class being compiled, to know whether `this` is allowed.
+struct ClassCompiler {
+    enclosing: *mut ClassCompiler,
+    has_superclass: bool,
+}
+
+impl Compiler {
+    fn new(typee: FunctionType) -> Self {
+        Self {
+            enclosing: null_mut(),
+            function: null_mut(),
+            typee,
+            locals: [Local {
+                name: Token::error("uninitialized", 0, 0),
+                depth: None,
+                is_captured: false,
+            }; U8_COUNT],
+            local_count: 0,
+            upvalues: [Upvalue {
+                index: 0,
+                is_local: false,
+            }; U8_COUNT],
+            scope_depth: 0,
+            identifiers: Table::new(),
+        }
     }
+}
 
+pub fn compile(vm: &mut Vm, source: &str) -> Result<*mut ObjFunction, Vec<Diagnostic>> {
     // Note: having trouble naming this type, so not able to store it in Parser, so
     // just explicitly passing it to methods instead
-    let token_iter = std::iter::from_coroutine(scan(source)).peekable();
+    let token_iter = std::iter::from_coroutine(scan(source)).fuse().peekable();
     let mut parser = Parser {
-        current: Token::error("uninitialized", 0),
-        previous: Token::error("uninitialized", 0),
+        current: Token::error("uninitialized", 0, 0),
+        previous: Token::error("uninitialized", 0, 0),
         had_error: false,
         panic_mode: false,
+        diagnostics: Vec::new(),
         token_iter: Box::new(token_iter),
         source,
+        vm,
+        class_compiler: null_mut(),
+        last_expression: None,
     };
+    let mut compiler = Compiler::new(FunctionType::Script);
+    parser.init_compiler(&mut compiler);
+
     parser.advance();
-    parser.expression();
-    parser.consume(TokenType::Eof, "Expect end of expression");
-    parser.end_compiler();
-    !parser.had_error
+    let mut ends_with_expression = false;
+    while !parser.match_token(TokenType::Eof) {
+        let start = parser.current_chunk().count;
+        parser.declaration();
+        let end = parser.current_chunk().count;
+        ends_with_expression = end > start && parser.last_expression == Some((start, end - 1));
+    }
+    if ends_with_expression && !parser.had_error {
+        // the script evaluates to its final expression statement, so return that value instead
+        // of popping it
+        parser.current_chunk().count -= 1;
+        parser.emit_byte(OpCode::Return as u8);
+    }
+    let function = parser.end_compiler();
+    if parser.had_error {
+        Err(parser.diagnostics)
+    } else {
+        Ok(function)
+    }
 }
 
 struct Parser<'iter> {
@@ -48,8 +146,15 @@ struct Parser<'iter> {
     previous: Token,
     had_error: bool,
     panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
     token_iter: Box<dyn Iterator<Item = Token> + 'iter>,
     source: &'iter str,
+    vm: &'iter mut Vm,
+    /// innermost class being compiled, null outside of a class body
+    class_compiler: *mut ClassCompiler,
+    /// offsets of the first byte and the trailing pop of the latest expression statement in the
+    /// current function
+    last_expression: Option<(usize, usize)>,
 }
 
 impl<'iter> Debug for Parser<'iter> {
@@ -59,15 +164,27 @@ impl<'iter> Debug for Parser<'iter> {
             .field("previous", &self.previous)
             .field("had_error", &self.had_error)
             .field("panic_mode", &self.panic_mode)
+            .field("diagnostics", &self.diagnostics)
             .field("source", &self.source)
             .finish()
     }
 }
 
 impl<'iter> Parser<'iter> {
+    // NOTE: lifetime here is incorrect
+    fn current(&self) -> &'static mut Compiler {
+        unsafe { &mut *self.vm.compiler }
+    }
+
+    // NOTE: lifetime here is incorrect
+    fn current_chunk(&self) -> &'static mut Chunk {
+        unsafe { &mut (*self.current().function).chunk }
+    }
+
     fn advance(&mut self) {
-        std::mem::swap(&mut self.previous, &mut self.current);
+        self.previous = self.current;
         loop {
+            // the scanner stops after Eof, so reading past the end keeps returning Eof
             let Some(token) = self.token_iter.next() else {
                 return;
             };
@@ -82,7 +199,7 @@ impl<'iter> Parser<'iter> {
     }
 
     fn consume(&mut self, token: TokenType, message: &'static str) {
-        if self.current.typee == token {
+        if self.check(token) {
             self.advance();
             return;
         }
@@ -90,46 +207,158 @@ impl<'iter> Parser<'iter> {
         self.error_at_current(message);
     }
 
-    fn emit_byte(&self, byte: u8) {
-        current_chunk().write_chunk(byte, self.previous.line);
+    fn check(&self, token: TokenType) -> bool {
+        self.current.typee == token
+    }
+
+    fn match_token(&mut self, token: TokenType) -> bool {
+        if !self.check(token) {
+            return false;
+        }
+        self.advance();
+        true
     }
 
-    fn emit_bytes(&self, byte1: u8, byte2: u8) {
+    fn emit_byte(&mut self, byte: u8) {
+        self.emit_byte_at(byte, self.previous);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
         self.emit_byte(byte1);
         self.emit_byte(byte2);
     }
 
-    fn end_compiler(&self) {
+    /// Emit a byte for an instruction that runtime errors should blame on `token` rather than the
+    /// last token parsed.
+    fn emit_byte_at(&mut self, byte: u8, token: Token) {
+        self.current_chunk()
+            .write_chunk(self.vm, byte, token.line, token.span());
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, token: Token) {
+        self.emit_byte_at(byte1, token);
+        self.emit_byte_at(byte2, token);
+    }
+
+    fn init_compiler(&mut self, compiler: &mut Compiler) {
+        compiler.enclosing = self.vm.compiler;
+        compiler.function = new_function(self.vm);
+        self.vm.compiler = compiler as *mut Compiler;
+
+        if compiler.typee != FunctionType::Script {
+            let name = self.lexeme(&self.previous);
+            unsafe { (*compiler.function).name = copy_string(self.vm, name) };
+        }
+
+        // nested functions share the script's copy of the source
+        let source = if compiler.enclosing.is_null() {
+            copy_string(self.vm, self.source)
+        } else {
+            unsafe { (*(*compiler.enclosing).function).source }
+        };
+        unsafe { (*compiler.function).source = source };
+
+        // slot zero holds the function being called, or the receiver for methods. Only the
+        // receiver can be named by user code, as `this`.
+        let slot_zero = match compiler.typee {
+            FunctionType::Method | FunctionType::Initializer => This,
+            FunctionType::Function | FunctionType::Script => Identifier,
+        };
+        compiler.locals[0] = Local {
+            name: Token::synthetic(slot_zero, self.previous.line),
+            depth: Some(0),
+            is_captured: false,
+        };
+        compiler.local_count = 1;
+    }
+
+    fn end_compiler(&mut self) -> *mut ObjFunction {
         self.emit_return();
+        let compiler = self.current();
+        let function = compiler.function;
 
         #[cfg(feature = "debug_print_code")]
         if !self.had_error {
-            let chunk = unsafe { &mut *crate::vm::VM.chunk };
-            chunk.disassemble_chunk(self.source.to_string());
+            let name = if unsafe { (*function).name.is_null() } {
+                "<script>"
+            } else {
+                unsafe { (*(*function).name).as_str() }
+            };
+            self.current_chunk().disassemble_chunk(name.to_string());
         }
+
+        compiler.identifiers.free_table(self.vm);
+        self.vm.compiler = compiler.enclosing;
+        self.last_expression = None;
+        function
     }
 
-    fn binary(&mut self) {
-        let operator_type = self.previous.typee;
-        let ParseRule((_, _, precedence)) = operator_type.rule();
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous;
+        let ParseRule((_, _, precedence)) = operator.typee.rule();
         self.parse_precedence(precedence.next());
 
-        match operator_type {
-            BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
-            EqualEqual => self.emit_byte(OpCode::Equal as u8),
-            Greater => self.emit_byte(OpCode::Greater as u8),
-            GreaterEqual => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
-            Less => self.emit_byte(OpCode::Less as u8),
-            LessEqual => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
-            Plus => self.emit_byte(OpCode::Add as u8),
-            Minus => self.emit_byte(OpCode::Subtract as u8),
-            Star => self.emit_byte(OpCode::Multiply as u8),
-            Slash => self.emit_byte(OpCode::Divide as u8),
+        match operator.typee {
+            BangEqual => self.emit_bytes_at(OpCode::Equal as u8, OpCode::Not as u8, operator),
+            EqualEqual => self.emit_byte_at(OpCode::Equal as u8, operator),
+            Greater => self.emit_byte_at(OpCode::Greater as u8, operator),
+            GreaterEqual => self.emit_bytes_at(OpCode::Less as u8, OpCode::Not as u8, operator),
+            Less => self.emit_byte_at(OpCode::Less as u8, operator),
+            LessEqual => self.emit_bytes_at(OpCode::Greater as u8, OpCode::Not as u8, operator),
+            Plus => self.emit_byte_at(OpCode::Add as u8, operator),
+            Minus => self.emit_byte_at(OpCode::Subtract as u8, operator),
+            Star => self.emit_byte_at(OpCode::Multiply as u8, operator),
+            Slash => self.emit_byte_at(OpCode::Divide as u8, operator),
             _ => unreachable!(),
         }
     }
 
-    fn literal(&mut self) {
+    fn call(&mut self, _can_assign: bool) {
+        let open_paren = self.previous;
+        let arg_count = self.argument_list();
+        // blame the whole argument list, from '(' to ')'
+        let arguments = open_paren.through(&self.previous);
+        self.emit_bytes_at(OpCode::Call as u8, arg_count, arguments);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.semantic_error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(Identifier, "Expect property name after '.'.");
+        let name_token = self.previous;
+        let name = self.identifier_constant(&name_token);
+
+        if can_assign && self.match_token(Equal) {
+            self.expression();
+            self.emit_operand_at(OpCode::SetProperty, name, name_token);
+        } else if self.match_token(LeftParen) {
+            // calling a method directly doesn't need a bound method
+            let arg_count = self.argument_list();
+            self.emit_operand_at(OpCode::Invoke, name, name_token);
+            self.emit_byte_at(arg_count, name_token);
+        } else {
+            self.emit_operand_at(OpCode::GetProperty, name, name_token);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.previous.typee {
             TokenType::False => self.emit_byte(OpCode::False as u8),
             TokenType::Nil => self.emit_byte(OpCode::Nil as u8),
@@ -138,7 +367,7 @@ impl<'iter> Parser<'iter> {
         }
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.consume(RightParen, "Expect ')' after expression.");
     }
@@ -147,34 +376,605 @@ impl<'iter> Parser<'iter> {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn number(&mut self) {
-        let start = self.previous.start();
-        let str_value = self
-            .source
-            .get(start..(start + self.previous.length))
-            .unwrap();
-        let value: f64 = str_value.parse::<f64>().unwrap();
-        self.emit_constant(Value::Double(value));
+    fn declaration(&mut self) {
+        if self.match_token(Class) {
+            self.class_declaration();
+        } else if self.match_token(Fun) {
+            self.fun_declaration();
+        } else if self.match_token(Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    /// Skip tokens until a likely statement boundary, so one error doesn't cause a cascade of
+    /// others and later errors are still reported.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.typee != Eof {
+            if self.previous.typee == Semicolon {
+                return;
+            }
+            match self.current.typee {
+                Class | Fun | Var | For | If | While | Print | Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_operand(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        let mut class_compiler = ClassCompiler {
+            enclosing: self.class_compiler,
+            has_superclass: false,
+        };
+        self.class_compiler = &mut class_compiler as *mut ClassCompiler;
+
+        if self.match_token(Less) {
+            self.consume(Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            let superclass_name = self.previous;
+            if self.lexeme(&class_name) == self.lexeme(&superclass_name) {
+                self.semantic_error("A class can't inherit from itself.");
+            }
+
+            // the superclass is stored in a local named `super`, in a scope wrapping the class
+            // body, so each method can capture it
+            self.begin_scope();
+            self.add_local(Token::synthetic(Super, superclass_name.line));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte_at(OpCode::Inherit as u8, superclass_name);
+            class_compiler.has_superclass = true;
+        }
+
+        // load the class so methods can be attached to it
+        self.named_variable(&class_name, false);
+        self.consume(LeftBrace, "Expect '{' before class body.");
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.method();
+        }
+        self.consume(RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop as u8);
+
+        if class_compiler.has_superclass {
+            self.end_scope();
+        }
+
+        self.class_compiler = class_compiler.enclosing;
+    }
+
+    fn method(&mut self) {
+        self.consume(Identifier, "Expect method name.");
+        let name = self.previous;
+        let constant = self.identifier_constant(&name);
+
+        let typee = if self.lexeme(&name) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(typee);
+        self.emit_operand(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a function can refer to itself, so it is initialized before its body is compiled
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, typee: FunctionType) {
+        let mut compiler = Compiler::new(typee);
+        self.init_compiler(&mut compiler);
+        // no matching end_scope, since the whole compiler goes away at the end of the body
+        self.begin_scope();
+
+        self.consume(LeftParen, "Expect '(' after function name.");
+        if !self.check(RightParen) {
+            loop {
+                let function = unsafe { &mut *self.current().function };
+                function.arity += 1;
+                if function.arity == U8_COUNT {
+                    self.semantic_error_at(self.current, "Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after parameters.");
+        self.consume(LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let function = self.end_compiler();
+        let constant = self.add_constant(Value::obj(function as *mut Obj));
+        self.emit_operand(OpCode::Closure, constant);
+
+        for upvalue in &compiler.upvalues[..unsafe { (*function).upvalue_count }] {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
     }
 
-    fn unary(&mut self) {
-        let operator_type = self.previous.typee;
+    fn parse_variable(&mut self, error_message: &'static str) -> usize {
+        self.consume(Identifier, error_message);
+
+        self.declare_variable();
+        if self.current().scope_depth > 0 {
+            // locals live on the stack, so they don't need a name constant
+            return 0;
+        }
+
+        let name = self.previous;
+        self.identifier_constant(&name)
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let str_value = self.lexeme(name);
+        let string = copy_string(self.vm, str_value);
+        let identifiers = &raw mut self.current().identifiers;
+        if let Some(index) = unsafe { (*identifiers).get(string) } {
+            return f64::try_from(index).unwrap() as usize;
+        }
+
+        // the constant keeps the name reachable while the table grows
+        let index = self.add_constant(Value::obj(string as *mut Obj));
+        unsafe { (*identifiers).set(self.vm, string, Value::number(index as f64)) };
+        index
+    }
+
+    fn lexeme(&self, token: &Token) -> &'iter str {
+        // keywords can be synthesized for implicit variables, so don't read those from the source
+        match token.typee {
+            This => return "this",
+            Super => return "super",
+            _ => {}
+        }
+        if token.length == 0 {
+            return "";
+        }
+
+        let start = token.start();
+        self.source.get(start..(start + token.length)).unwrap()
+    }
+
+    fn declare_variable(&mut self) {
+        let compiler = self.current();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous;
+        for local in compiler.locals[..compiler.local_count].iter().rev() {
+            if local
+                .depth
+                .is_some_and(|depth| depth < compiler.scope_depth)
+            {
+                break;
+            }
+
+            if self.lexeme(&name) == self.lexeme(&local.name) {
+                self.semantic_error("Already a variable with this name in this scope.");
+            }
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        let compiler = self.current();
+        if compiler.local_count == U8_COUNT {
+            self.semantic_error("Too many local variables in function.");
+            return;
+        }
+
+        compiler.locals[compiler.local_count] = Local {
+            name,
+            depth: None,
+            is_captured: false,
+        };
+        compiler.local_count += 1;
+    }
+
+    fn resolve_local(&mut self, compiler: &Compiler, name: &Token) -> Option<u8> {
+        for (i, local) in compiler.locals[..compiler.local_count]
+            .iter()
+            .enumerate()
+            .rev()
+        {
+            if self.lexeme(name) == self.lexeme(&local.name) {
+                if local.depth.is_none() {
+                    self.semantic_error("Can't read local variable in its own initializer.");
+                }
+                return Some(i as u8);
+            }
+        }
+
+        None
+    }
+
+    /// Resolve a variable declared in any of the enclosing functions, adding upvalues to every
+    /// function between the declaration and this one.
+    fn resolve_upvalue(&mut self, compiler: &mut Compiler, name: &Token) -> Option<u8> {
+        if compiler.enclosing.is_null() {
+            return None;
+        }
+        let enclosing = unsafe { &mut *compiler.enclosing };
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            enclosing.locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(compiler, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, compiler: &mut Compiler, index: u8, is_local: bool) -> u8 {
+        let function = unsafe { &mut *compiler.function };
+        let upvalue_count = function.upvalue_count;
+
+        // a closure can reference the same variable multiple times
+        for (i, upvalue) in compiler.upvalues[..upvalue_count].iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return i as u8;
+            }
+        }
+
+        if upvalue_count == U8_COUNT {
+            self.semantic_error("Too many closure variables in function.");
+            return 0;
+        }
+
+        compiler.upvalues[upvalue_count] = Upvalue { index, is_local };
+        function.upvalue_count += 1;
+        upvalue_count as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.current();
+        // globals are initialized by DefineGlobal
+        if compiler.scope_depth == 0 {
+            return;
+        }
+        compiler.locals[compiler.local_count - 1].depth = Some(compiler.scope_depth);
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_operand(OpCode::DefineGlobal, global);
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(Print) {
+            self.print_statement();
+        } else if self.match_token(Return) {
+            self.return_statement();
+        } else if self.match_token(For) {
+            self.for_statement();
+        } else if self.match_token(If) {
+            self.if_statement();
+        } else if self.match_token(While) {
+            self.while_statement();
+        } else if self.match_token(LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.declaration();
+        }
+
+        self.consume(RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let compiler = self.current();
+        compiler.scope_depth -= 1;
+
+        // pop every local that belonged to the scope we just left
+        while compiler.local_count > 0
+            && compiler.locals[compiler.local_count - 1]
+                .depth
+                .is_some_and(|depth| depth > compiler.scope_depth)
+        {
+            if compiler.locals[compiler.local_count - 1].is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            compiler.local_count -= 1;
+        }
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(Semicolon) {
+            // no initializer
+        } else if self.match_token(Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().count;
+        let mut exit_jump = None;
+        if !self.match_token(Semicolon) {
+            self.expression();
+            self.consume(Semicolon, "Expect ';' after loop condition.");
+
+            // jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        if !self.match_token(RightParen) {
+            // the increment is compiled before the body, so jump over it and loop back to it
+            // after the body runs
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().count;
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.match_token(Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().count;
+        self.consume(LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.current().typee == FunctionType::Script {
+            self.semantic_error("Can't return from top-level code.");
+        }
+
+        if self.match_token(Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current().typee == FunctionType::Initializer {
+                self.semantic_error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return as u8);
+        }
+    }
+
+    fn expression_statement(&mut self) {
+        let start = self.current_chunk().count;
+        self.expression();
+        self.consume(Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop as u8);
+        self.last_expression = Some((start, self.current_chunk().count - 1));
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.lexeme(&self.previous);
+        let value = parse_number(lexeme).expect("numbers are checked by the scanner");
+        self.emit_constant(Value::number(value));
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let lexeme = self.lexeme(&self.previous);
+        let string = if let Some(raw) = lexeme.strip_prefix('r') {
+            // trim the hashes and quotes around the contents, which are kept as written
+            let hashes = raw.len() - raw.trim_start_matches('#').len();
+            copy_string(self.vm, &raw[hashes + 1..raw.len() - hashes - 1])
+        } else {
+            // trim the surrounding quotes
+            let contents = &lexeme[1..lexeme.len() - 1];
+            if contents.contains('\\') {
+                copy_string(self.vm, &unescape(contents))
+            } else {
+                copy_string(self.vm, contents)
+            }
+        };
+        self.emit_constant(Value::obj(string as *mut Obj));
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        // if the left side is falsey skip the right side and leave the left value on the stack
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        // if the left side is truthy skip the right side and leave the left value on the stack
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous;
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let (get_op, set_op, arg) = if let Some(arg) = self.resolve_local(self.current(), name) {
+            (OpCode::GetLocal, OpCode::SetLocal, arg as usize)
+        } else if let Some(arg) = self.resolve_upvalue(self.current(), name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, arg as usize)
+        } else {
+            let arg = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+        };
+
+        if can_assign && self.match_token(Equal) {
+            self.expression();
+            self.emit_operand_at(set_op, arg, *name);
+        } else {
+            self.emit_operand_at(get_op, arg, *name);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        let class = self.class_compiler;
+        if class.is_null() {
+            self.semantic_error("Can't use 'super' outside of a class.");
+        } else if !unsafe { (*class).has_superclass } {
+            self.semantic_error("Can't use 'super' in a class with no superclass.");
+        }
+
+        self.consume(Dot, "Expect '.' after 'super'.");
+        self.consume(Identifier, "Expect superclass method name.");
+        let name_token = self.previous;
+        let name = self.identifier_constant(&name_token);
+
+        let line = self.previous.line;
+        self.named_variable(&Token::synthetic(This, line), false);
+        if self.match_token(LeftParen) {
+            // calling a super method directly doesn't need a bound method
+            let arg_count = self.argument_list();
+            self.named_variable(&Token::synthetic(Super, line), false);
+            self.emit_operand_at(OpCode::SuperInvoke, name, name_token);
+            self.emit_byte_at(arg_count, name_token);
+        } else {
+            self.named_variable(&Token::synthetic(Super, line), false);
+            self.emit_operand_at(OpCode::GetSuper, name, name_token);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compiler.is_null() {
+            self.semantic_error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is resolved like any other variable, so closures capture it too
+        self.variable(false);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous;
         self.parse_precedence(Precedence::Unary);
 
-        match operator_type {
-            Bang => self.emit_byte(OpCode::Not as u8),
-            Minus => self.emit_byte(OpCode::Negate as u8),
+        match operator.typee {
+            Bang => self.emit_byte_at(OpCode::Not as u8, operator),
+            Minus => self.emit_byte_at(OpCode::Negate as u8, operator),
             _ => unreachable!(),
         }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
-        if let ParseRule((Some(prefix_rule), _, _)) = TokenType::rule(self.previous.typee) {
-            prefix_rule(self);
-        } else {
-            self.error("expect expression");
-        }
+        let ParseRule((Some(prefix_rule), _, _)) = TokenType::rule(self.previous.typee) else {
+            self.error("Expect expression.");
+            return;
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
 
         while let ParseRule((_, _, current_precendence)) = TokenType::rule(self.current.typee)
             && precedence <= current_precendence
@@ -183,46 +983,135 @@ impl<'iter> Parser<'iter> {
             let ParseRule((_, Some(infix_rule), _)) = TokenType::rule(self.previous.typee) else {
                 return;
             };
-            infix_rule(self);
+            infix_rule(self, can_assign);
+        }
+
+        if can_assign && self.match_token(Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    /// Emit a jump with a placeholder operand, returning the offset of the operand so it can be
+    /// patched once the target is known.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().count - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let chunk = self.current_chunk();
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = chunk.count - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.semantic_error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        unsafe {
+            *chunk.code.add(offset) = high;
+            *chunk.code.add(offset + 1) = low;
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        // +2 to also jump back over the operand
+        let offset = self.current_chunk().count - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.semantic_error("Loop body too large.");
         }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
     }
 
-    fn emit_return(&self) {
+    fn emit_return(&mut self) {
+        // initializers implicitly return the instance, other functions without a return
+        // statement return nil
+        if self.current().typee == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
         self.emit_byte(OpCode::Return as u8);
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = current_chunk().add_constant(value);
-        // TODO: this doesn't look quite right
-        if constant > u8::MAX.into() {
-            self.error("Too many constants in one chunk.");
+    /// Add a constant that can be referred to by a 24 bit operand.
+    fn add_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(self.vm, value);
+        if constant > MAX_LONG_CONSTANT {
+            self.semantic_error("Too many constants in one chunk.");
             0
         } else {
-            constant as u8
+            constant
         }
     }
 
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+        let constant = self.add_constant(value);
+        self.emit_operand(OpCode::Constant, constant);
+    }
+
+    fn emit_operand(&mut self, instruction: OpCode, operand: usize) {
+        self.emit_operand_at(instruction, operand, self.previous);
+    }
+
+    /// Emit an instruction with a one byte operand, or its long form if `operand` is a constant
+    /// index that doesn't fit in a byte.
+    fn emit_operand_at(&mut self, instruction: OpCode, operand: usize, token: Token) {
+        if let Ok(operand) = u8::try_from(operand) {
+            self.emit_bytes_at(instruction as u8, operand, token);
+        } else {
+            // the operand is written big endian, like jump offsets
+            let [_, high, middle, low] = (operand as u32).to_be_bytes();
+            self.emit_bytes_at(instruction.long() as u8, high, token);
+            self.emit_bytes_at(middle, low, token);
+        }
     }
 
     fn error_at_current(&mut self, message: &'static str) {
-        error_at(
-            &self.current,
-            message,
-            &mut self.had_error,
-            &mut self.panic_mode,
-        );
+        self.error_at(self.current, message);
     }
 
     fn error(&mut self, message: &'static str) {
-        error_at(
-            &self.previous,
-            message,
-            &mut self.had_error,
-            &mut self.panic_mode,
-        );
+        self.error_at(self.previous, message);
+    }
+
+    fn error_at(&mut self, token: Token, message: &'static str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.report(token, message);
+    }
+
+    /// Report a mistake in code that parsed fine, like `return` at the top level. The parser isn't
+    /// lost, so unlike `error` this doesn't skip ahead to the next statement.
+    fn semantic_error(&mut self, message: &'static str) {
+        self.semantic_error_at(self.previous, message);
+    }
+
+    fn semantic_error_at(&mut self, token: Token, message: &'static str) {
+        // while panicking, the parser may have gotten here by misreading the code
+        if self.panic_mode {
+            return;
+        }
+        self.report(token, message);
+    }
+
+    fn report(&mut self, token: Token, message: &'static str) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: token.span(),
+            line: token.line,
+            column: token.column,
+            notes: Vec::new(),
+        });
+        self.had_error = true;
     }
 }
 
@@ -249,12 +1138,15 @@ impl Precedence {
 
 struct ParseRule<'iter>((Option<ParseFn<'iter>>, Option<ParseFn<'iter>>, Precedence));
 
-type ParseFn<'iter> = fn(&mut Parser<'iter>);
+type ParseFn<'iter> = fn(&mut Parser<'iter>, bool);
 
 impl TokenType {
     fn rule<'a>(self) -> ParseRule<'a> {
         match self {
-            LeftParen => ParseRule((Some(Parser::grouping), None, Precedence::None)),
+            LeftParen => ParseRule((Some(Parser::grouping), Some(Parser::call), Precedence::Call)),
+            Dot => ParseRule((None, Some(Parser::dot), Precedence::Call)),
+            Super => ParseRule((Some(Parser::super_), None, Precedence::None)),
+            This => ParseRule((Some(Parser::this), None, Precedence::None)),
             Minus => ParseRule((Some(Parser::unary), Some(Parser::binary), Precedence::Term)),
             Plus => ParseRule((None, Some(Parser::binary), Precedence::Term)),
             Slash => ParseRule((None, Some(Parser::binary), Precedence::Factor)),
@@ -265,27 +1157,54 @@ impl TokenType {
                 ParseRule((None, Some(Parser::binary), Precedence::Comparison))
             }
             Number => ParseRule((Some(Parser::number), None, Precedence::None)),
+            String => ParseRule((Some(Parser::string), None, Precedence::None)),
+            Identifier => ParseRule((Some(Parser::variable), None, Precedence::None)),
+            And => ParseRule((None, Some(Parser::and), Precedence::And)),
+            Or => ParseRule((None, Some(Parser::or), Precedence::Or)),
             Nil | False | True => ParseRule((Some(Parser::literal), None, Precedence::None)),
-            RightParen | LeftBrace | RightBrace | Comma | Dot | Semicolon | Equal | Identifier
-            | String | And | Class | Else | For | Fun | If | Or | Print | Return | Super | This
-            | Var | While | Error | Eof => ParseRule((None, None, Precedence::None)),
+            RightParen | LeftBrace | RightBrace | Comma | Semicolon | Equal | Class | Else
+            | For | Fun | If | Print | Return | Var | While | Error | Eof => {
+                ParseRule((None, None, Precedence::None))
+            }
         }
     }
 }
 
-fn error_at(token: &Token, message: &'static str, had_error: &mut bool, panic_mode: &mut bool) {
-    if *panic_mode {
-        return;
-    }
-    *panic_mode = true;
-    eprint!("[line {}] Error", token.line);
+/// Replace the escape sequences in the contents of a string literal with the characters they
+/// stand for. The scanner already rejected invalid escapes.
+fn unescape(contents: &str) -> std::string::String {
+    let mut chars = contents.char_indices().peekable();
+    std::iter::from_fn(|| match chars.next()? {
+        (_, '\\') => Some(escape(&mut chars).expect("escapes are checked by the scanner")),
+        (_, ch) => Some(ch),
+    })
+    .collect()
+}
 
-    match token.typee {
-        TokenType::Eof => eprint!(" at end"),
-        TokenType::Error => {}
-        _ => eprint!(" at '{:.*}'", token.length, token.start()),
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::vm::Vm;
+
+    fn messages(source: &str) -> Vec<String> {
+        let mut vm = Vm::new();
+        match compile(&mut vm, source) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect(),
+        }
     }
 
-    eprintln!(": {message}");
-    *had_error = true;
+    #[test]
+    fn long_jumps_dont_skip_the_enclosing_block() {
+        // each `a;` is three bytes, so the if body is more than a jump can skip
+        let body = "a;".repeat(22_000);
+        let source = format!("{{ if (true) {{ {body} }} }}\nprint 1 +;");
+        assert_eq!(
+            messages(&source),
+            ["Too much code to jump over.", "Expect expression."]
+        );
+    }
 }
@@ -1,4 +1,7 @@
-use crate::chunk::{Chunk, OpCode};
+use crate::{
+    chunk::{Chunk, OpCode},
+    object::as_function,
+};
 
 #[allow(unused)]
 impl Chunk {
@@ -15,10 +18,11 @@ impl Chunk {
     /// - offset cannot go outside of the allocation of chuck
     pub unsafe fn disassemble_instruction(&self, offset: isize) -> isize {
         print!("{:04} ", offset);
-        if offset > 0 && unsafe { *self.lines.offset(offset) == *self.lines.offset(offset - 1) } {
+        let line = self.get_line(offset as usize);
+        if offset > 0 && line == self.get_line(offset as usize - 1) {
             print!("   | ");
         } else {
-            print!("{:04} ", unsafe { *self.lines.offset(offset) });
+            print!("{line:04} ");
         }
 
         // SAFETY: is ensured by caller
@@ -29,7 +33,8 @@ impl Chunk {
             OpCode::Subtract => simple_instruction("Subtract", offset),
             OpCode::Multiply => simple_instruction("Multiply", offset),
             OpCode::Divide => simple_instruction("Divide", offset),
-            OpCode::Constant => self.constant_instruction("Constant", offset),
+            OpCode::Constant => self.constant_instruction("Constant", offset, false),
+            OpCode::ConstantLong => self.constant_instruction("ConstantLong", offset, true),
             OpCode::Nil => simple_instruction("Nil", offset),
             OpCode::True => simple_instruction("True", offset),
             OpCode::False => simple_instruction("False", offset),
@@ -38,16 +43,113 @@ impl Chunk {
             OpCode::Less => simple_instruction("Less", offset),
             OpCode::Not => simple_instruction("Not", offset),
             OpCode::Negate => simple_instruction("Negate", offset),
+            OpCode::Print => simple_instruction("Print", offset),
+            OpCode::Pop => simple_instruction("Pop", offset),
+            OpCode::DefineGlobal => self.constant_instruction("DefineGlobal", offset, false),
+            OpCode::GetGlobal => self.constant_instruction("GetGlobal", offset, false),
+            OpCode::SetGlobal => self.constant_instruction("SetGlobal", offset, false),
+            OpCode::GetLocal => self.byte_instruction("GetLocal", offset),
+            OpCode::SetLocal => self.byte_instruction("SetLocal", offset),
+            OpCode::Jump => self.jump_instruction("Jump", 1, offset),
+            OpCode::JumpIfFalse => self.jump_instruction("JumpIfFalse", 1, offset),
+            OpCode::Loop => self.jump_instruction("Loop", -1, offset),
+            OpCode::Call => self.byte_instruction("Call", offset),
+            OpCode::Closure => self.closure_instruction("Closure", offset, false),
+            OpCode::GetUpvalue => self.byte_instruction("GetUpvalue", offset),
+            OpCode::SetUpvalue => self.byte_instruction("SetUpvalue", offset),
+            OpCode::CloseUpvalue => simple_instruction("CloseUpvalue", offset),
+            OpCode::Class => self.constant_instruction("Class", offset, false),
+            OpCode::GetProperty => self.constant_instruction("GetProperty", offset, false),
+            OpCode::SetProperty => self.constant_instruction("SetProperty", offset, false),
+            OpCode::Method => self.constant_instruction("Method", offset, false),
+            OpCode::Invoke => self.invoke_instruction("Invoke", offset, false),
+            OpCode::Inherit => simple_instruction("Inherit", offset),
+            OpCode::GetSuper => self.constant_instruction("GetSuper", offset, false),
+            OpCode::SuperInvoke => self.invoke_instruction("SuperInvoke", offset, false),
+            OpCode::DefineGlobalLong => self.constant_instruction("DefineGlobalLong", offset, true),
+            OpCode::GetGlobalLong => self.constant_instruction("GetGlobalLong", offset, true),
+            OpCode::SetGlobalLong => self.constant_instruction("SetGlobalLong", offset, true),
+            OpCode::ClosureLong => self.closure_instruction("ClosureLong", offset, true),
+            OpCode::ClassLong => self.constant_instruction("ClassLong", offset, true),
+            OpCode::GetPropertyLong => self.constant_instruction("GetPropertyLong", offset, true),
+            OpCode::SetPropertyLong => self.constant_instruction("SetPropertyLong", offset, true),
+            OpCode::MethodLong => self.constant_instruction("MethodLong", offset, true),
+            OpCode::InvokeLong => self.invoke_instruction("InvokeLong", offset, true),
+            OpCode::GetSuperLong => self.constant_instruction("GetSuperLong", offset, true),
+            OpCode::SuperInvokeLong => self.invoke_instruction("SuperInvokeLong", offset, true),
         }
     }
 
-    fn constant_instruction(&self, name: &'static str, offset: isize) -> isize {
-        let constant = unsafe { *self.code.offset(offset + 1) };
-        print!("{name:<16} {offset:04} ");
+    /// The constant index after the instruction at `offset`, one byte or 24 bits for the long
+    /// forms, and the offset right after it.
+    fn constant_operand(&self, offset: isize, long: bool) -> (usize, isize) {
+        if !long {
+            return (
+                unsafe { *self.code.offset(offset + 1) } as usize,
+                offset + 2,
+            );
+        }
+        let constant = u32::from_be_bytes(unsafe {
+            [
+                0,
+                *self.code.offset(offset + 1),
+                *self.code.offset(offset + 2),
+                *self.code.offset(offset + 3),
+            ]
+        });
+        (constant as usize, offset + 4)
+    }
+
+    fn constant_instruction(&self, name: &'static str, offset: isize, long: bool) -> isize {
+        let (constant, next) = self.constant_operand(offset, long);
+        print!("{name:<16} {constant:04} ");
         self.constants.print_value(constant);
         println!();
+        next
+    }
+
+    fn invoke_instruction(&self, name: &'static str, offset: isize, long: bool) -> isize {
+        let (constant, next) = self.constant_operand(offset, long);
+        let arg_count = unsafe { *self.code.offset(next) };
+        print!("{name:<16} ({arg_count} args) {constant:04} ");
+        self.constants.print_value(constant);
+        println!();
+        next + 1
+    }
+
+    /// Closure is followed by a pair of operands for each upvalue the function captures.
+    fn closure_instruction(&self, name: &'static str, offset: isize, long: bool) -> isize {
+        let (constant, next) = self.constant_operand(offset, long);
+        print!("{name:<16} {constant:04} ");
+        self.constants.print_value(constant);
+        println!();
+
+        let function = unsafe { &*as_function(*self.constants.values.add(constant)) };
+        let mut offset = next;
+        for _ in 0..function.upvalue_count {
+            let is_local = unsafe { *self.code.offset(offset) };
+            let index = unsafe { *self.code.offset(offset + 1) };
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            println!("{:04}    |                     {kind} {index}", offset);
+            offset += 2;
+        }
+        offset
+    }
+
+    fn byte_instruction(&self, name: &'static str, offset: isize) -> isize {
+        let slot = unsafe { *self.code.offset(offset + 1) };
+        println!("{name:<16} {slot:04}");
         offset + 2
     }
+
+    fn jump_instruction(&self, name: &'static str, sign: isize, offset: isize) -> isize {
+        let jump = u16::from_be_bytes(unsafe {
+            [*self.code.offset(offset + 1), *self.code.offset(offset + 2)]
+        });
+        let target = offset + 3 + sign * jump as isize;
+        println!("{name:<16} {offset:04} -> {target}");
+        offset + 3
+    }
 }
 
 fn simple_instruction(name: &'static str, offset: isize) -> isize {
@@ -0,0 +1,162 @@
+use std::fmt::Display;
+
+/// How bad a diagnostic is. Only errors stop a script from compiling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => f.write_str("Error"),
+            Severity::Warning => f.write_str("Warning"),
+        }
+    }
+}
+
+/// A range of bytes in the source, `end` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A problem found in the source while compiling it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    /// 1 based
+    pub line: usize,
+    /// 1 based, counted in characters
+    pub column: usize,
+    /// extra context shown after the message
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Format the diagnostic the way clox reports errors, naming the source it points at.
+    ///
+    /// `source` must be the source the diagnostic was reported for.
+    pub fn render(&self, source: &str) -> String {
+        let location = match source.get(self.span.start..self.span.end) {
+            Some("") if self.span.start == source.len() => " at end".to_string(),
+            Some(lexeme) if !lexeme.is_empty() && !lexeme.contains('\n') => {
+                format!(" at '{lexeme}'")
+            }
+            _ => String::new(),
+        };
+        let mut rendered = format!(
+            "[line {}] {}{location}: {}",
+            self.line, self.severity, self.message
+        );
+        let (line_start, line_end) = line_bounds(source, self.span.start);
+        rendered.push('\n');
+        rendered.push_str(&quote(
+            &source[line_start..line_end],
+            self.line,
+            self.column,
+            self.span.end - self.span.start,
+        ));
+        for note in &self.notes {
+            rendered.push_str(&format!("\n  note: {note}"));
+        }
+        rendered
+    }
+}
+
+/// Byte range of the line containing `offset`, without the newline.
+pub(crate) fn line_bounds(source: &str, offset: usize) -> (usize, usize) {
+    let start = source[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |newline| offset + newline);
+    (start, end)
+}
+
+/// Show a line of source with `length` bytes underlined from `column`, like
+///
+/// ```text
+///   2 | print a b;
+///     |         ^
+/// ```
+pub(crate) fn quote(source_line: &str, line: usize, column: usize, length: usize) -> String {
+    let start = source_line
+        .char_indices()
+        .nth(column - 1)
+        .map_or(source_line.len(), |(start, _)| start);
+    let end = (start + length).min(source_line.len());
+    // an empty span, like the end of the file, still gets a caret
+    let width = source_line[start..end].chars().count().max(1);
+
+    let gutter = line.to_string();
+    format!(
+        "{gutter} | {source_line}\n{} | {}{}",
+        " ".repeat(gutter.len()),
+        " ".repeat(column - 1),
+        "^".repeat(width)
+    )
+}
+
+/// Formats the diagnostic without the source, see [`Diagnostic::render`] to include it.
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.line, self.column, self.severity, self.message
+        )?;
+        for note in &self.notes {
+            write!(f, "\n  note: {note}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Severity, Span};
+
+    fn diagnostic(start: usize, end: usize) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: "Expect ';' after expression.".to_string(),
+            span: Span { start, end },
+            line: 1,
+            column: start + 1,
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn render_names_the_token() {
+        let source = "print a b";
+        assert_eq!(
+            diagnostic(8, 9).render(source),
+            "[line 1] Error at 'b': Expect ';' after expression.\n\
+             1 | print a b\n  \
+               |         ^"
+        );
+        assert_eq!(
+            diagnostic(9, 9).render(source),
+            "[line 1] Error at end: Expect ';' after expression.\n\
+             1 | print a b\n  \
+               |          ^"
+        );
+    }
+
+    #[test]
+    fn notes_follow_the_message() {
+        let mut diagnostic = diagnostic(8, 9);
+        diagnostic.notes.push("statements end with ';'".to_string());
+        assert_eq!(
+            diagnostic.to_string(),
+            "[line 1:9] Error: Expect ';' after expression.\n  note: statements end with ';'"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Display,
+    rc::Rc,
+};
+
+use crate::{
+    object::{as_string, is_string},
+    value::{self, is_bool, is_nil, is_number, is_obj},
+};
+
+/// Objects the host holds through a [`Value`]. The gc marks them like any other root.
+pub(crate) struct Roots {
+    /// cleared when the vm is dropped, its objects are freed after that
+    alive: Cell<bool>,
+    /// one slot per handle, free slots hold nil
+    slots: RefCell<Vec<value::Value>>,
+    free: RefCell<Vec<usize>>,
+}
+
+impl Roots {
+    pub(crate) fn new() -> Rc<Roots> {
+        Rc::new(Roots {
+            alive: Cell::new(true),
+            slots: RefCell::new(Vec::new()),
+            free: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn hold(&self, value: value::Value) -> usize {
+        let mut slots = self.slots.borrow_mut();
+        match self.free.borrow_mut().pop() {
+            Some(slot) => {
+                slots[slot] = value;
+                slot
+            }
+            None => {
+                slots.push(value);
+                slots.len() - 1
+            }
+        }
+    }
+
+    fn release(&self, slot: usize) {
+        if self.alive.get() {
+            self.slots.borrow_mut()[slot] = value::Value::NIL;
+            self.free.borrow_mut().push(slot);
+        }
+    }
+
+    /// Every value a handle refers to.
+    pub(crate) fn held(&self) -> Vec<value::Value> {
+        self.slots.borrow().clone()
+    }
+
+    /// Called once the vm is dropped, after which handles can't reach its objects.
+    pub(crate) fn release_all(&self) {
+        self.alive.set(false);
+        self.slots.borrow_mut().clear();
+        self.free.borrow_mut().clear();
+    }
+}
+
+/// Keeps an object alive while the host holds it.
+struct Handle {
+    roots: Rc<Roots>,
+    slot: usize,
+}
+
+impl Handle {
+    fn value(&self) -> value::Value {
+        assert!(self.roots.alive.get(), "value outlived its vm");
+        self.roots.slots.borrow()[self.slot]
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Self {
+        let slot = if self.roots.alive.get() {
+            self.roots.hold(self.value())
+        } else {
+            self.slot
+        };
+        Handle {
+            roots: Rc::clone(&self.roots),
+            slot,
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.roots.release(self.slot);
+    }
+}
+
+/// A lox value passed between the host and a vm.
+///
+/// Objects such as strings are owned by the vm that created them, and stay alive at least as long
+/// as a `Value` refers to them. Using one after its vm was dropped panics.
+#[derive(Clone)]
+pub struct Value(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    /// numbers, booleans and nil, which don't live on the heap
+    Plain(value::Value),
+    Object(Handle),
+}
+
+impl Value {
+    pub const NIL: Value = Value(Repr::Plain(value::Value::NIL));
+
+    pub const fn number(value: f64) -> Value {
+        Value(Repr::Plain(value::Value::number(value)))
+    }
+
+    pub const fn boolean(value: bool) -> Value {
+        Value(Repr::Plain(value::Value::boolean(value)))
+    }
+
+    /// Hand a value of the vm owning `roots` to the host.
+    pub(crate) fn new(roots: &Rc<Roots>, value: value::Value) -> Value {
+        if is_obj(value) {
+            Value(Repr::Object(Handle {
+                roots: Rc::clone(roots),
+                slot: roots.hold(value),
+            }))
+        } else {
+            Value(Repr::Plain(value))
+        }
+    }
+
+    /// The value to use in the vm owning `roots`, or None if it comes from a different vm.
+    pub(crate) fn get(&self, roots: &Rc<Roots>) -> Option<value::Value> {
+        match &self.0 {
+            Repr::Plain(value) => Some(*value),
+            Repr::Object(handle) => Rc::ptr_eq(&handle.roots, roots).then(|| handle.value()),
+        }
+    }
+
+    fn value(&self) -> value::Value {
+        match &self.0 {
+            Repr::Plain(value) => *value,
+            Repr::Object(handle) => handle.value(),
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        is_nil(self.value())
+    }
+
+    pub fn is_number(&self) -> bool {
+        is_number(self.value())
+    }
+
+    pub fn is_bool(&self) -> bool {
+        is_bool(self.value())
+    }
+
+    pub fn is_string(&self) -> bool {
+        is_string(self.value())
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        f64::try_from(self.value()).ok()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        bool::try_from(self.value()).ok()
+    }
+
+    /// A copy of the string's contents, owned so it can outlive the vm.
+    pub fn as_string(&self) -> Option<String> {
+        let value = self.value();
+        // Safety: the handle keeps the string alive while it's being copied
+        is_string(value).then(|| unsafe { (*as_string(value)).as_str().to_string() })
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.value().fmt(f)
+    }
+}
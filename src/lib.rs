@@ -0,0 +1,37 @@
+//! A bytecode interpreter for lox, following the clox half of Crafting Interpreters.
+//!
+//! Embedding a script:
+//!
+//! ```
+//! use old_iron_lox::{Value, Vm};
+//!
+//! fn double(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+//!     let n = args[0].as_number().ok_or("Argument must be a number.")?;
+//!     Ok(Value::number(n * 2.0))
+//! }
+//!
+//! let mut vm = Vm::new();
+//! vm.define_native("double", double);
+//! vm.set_global("x", Value::number(20.0));
+//! let result = vm.interpret("double(x) + 2;").unwrap();
+//! assert_eq!(result.as_number(), Some(42.0));
+//! ```
+#![feature(coroutines, coroutine_trait, iter_from_coroutine)]
+
+mod chunk;
+mod compiler;
+mod debug;
+mod diagnostic;
+mod handle;
+mod memory;
+mod native;
+mod object;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+pub use diagnostic::{Diagnostic, Severity, Span};
+pub use handle::Value;
+pub use object::NativeFn;
+pub use vm::{Limits, LoxError, Script, TraceFrame, Vm};
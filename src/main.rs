@@ -1,64 +1,58 @@
-#![feature(coroutines, coroutine_trait, iter_from_coroutine)]
-#![feature(type_alias_impl_trait)]
-
 use std::{
     env, fs,
     io::{Write, stdin},
     process::exit,
 };
 
-use vm::{InterpretError, free_vm, init_vm, interpret};
-
-mod chunk;
-mod compiler;
-mod debug;
-mod memory;
-mod scanner;
-mod value;
-mod vm;
+use old_iron_lox::{LoxError, Vm};
 
 fn main() {
-    init_vm();
+    let mut vm = Vm::new();
 
     let args: Vec<String> = env::args().collect();
 
     match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
+        1 => repl(&mut vm),
+        2 => run_file(&mut vm, &args[1]),
         _ => {
             println!("Usage: clox [path]");
             exit(64);
         }
     }
-
-    free_vm();
-    exit(0);
 }
 
-fn repl() {
+fn repl(vm: &mut Vm) {
     let mut line = String::new();
     println!("Starting repl...");
     loop {
         print!("> ");
 
         std::io::stdout().flush().unwrap();
-        stdin().read_line(&mut line).expect("Did not get line");
+        if stdin().read_line(&mut line).expect("Did not get line") == 0 {
+            // end of input
+            println!();
+            break;
+        }
 
-        interpret(&line).unwrap();
+        // report the error and keep reading lines
+        if let Err(err) = vm.interpret(&line) {
+            eprintln!("{}", err.render(&line));
+        }
         line.clear();
     }
 }
 
-fn run_file(path: &str) {
+fn run_file(vm: &mut Vm, path: &str) {
     let Ok(source) = fs::read_to_string(path) else {
         println!("Could not open file {path}");
         exit(74);
     };
     println!("running {path}");
-    let result = interpret(&source);
-    match result {
-        Err(InterpretError::CompileError) => todo!(),
-        Err(InterpretError::RuntimeError) => todo!(),
-        Ok(_) => {}
+    if let Err(err) = vm.interpret(&source) {
+        eprintln!("{}", err.render(&source));
+        match err {
+            LoxError::Compile { .. } => exit(65),
+            LoxError::Runtime { .. } => exit(70),
+        }
     }
 }
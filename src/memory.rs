@@ -1,37 +1,79 @@
 use std::{
-    alloc::{alloc, dealloc, realloc, Layout},
+    alloc::{Layout, alloc, dealloc, realloc},
     process::exit,
     ptr::null_mut,
 };
 
+use crate::{
+    compiler::mark_compiler_roots,
+    object::{
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString,
+        ObjType, ObjUpvalue,
+    },
+    table::Table,
+    value::{Value, ValueArray, as_obj, is_obj},
+    vm::Vm,
+};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
 // TODO: none of this code is dealing with padding and alignment correctly.
 
 #[inline(always)]
 pub const fn grow_capacity(capacity: usize) -> usize {
-    if capacity < 8 {
-        8
-    } else {
-        capacity * 2
-    }
+    if capacity < 8 { 8 } else { capacity * 2 }
+}
+
+/// Safety:
+/// - the returned memory is uninitialized and must be written before it is read.
+pub unsafe fn allocate<T>(vm: &mut Vm, count: usize) -> *mut T {
+    unsafe { reallocate(vm, null_mut(), 0, size_of::<T>() * count) as *mut T }
+}
+
+/// Safety:
+/// - pointer was allocated by `allocate::<T>(1)`.
+pub unsafe fn free<T>(vm: &mut Vm, pointer: *mut T) {
+    unsafe { reallocate(vm, pointer as *mut u8, size_of::<T>(), 0) };
 }
 
-pub unsafe fn grow_array<T>(pointer: *mut T, old_count: usize, new_count: usize) -> *mut T {
+pub unsafe fn grow_array<T>(
+    vm: &mut Vm,
+    pointer: *mut T,
+    old_count: usize,
+    new_count: usize,
+) -> *mut T {
     let size_of_t = size_of::<T>();
-    reallocate(
-        pointer as *mut u8,
-        size_of_t * old_count,
-        size_of_t * new_count,
-    ) as *mut T
+    unsafe {
+        reallocate(
+            vm,
+            pointer as *mut u8,
+            size_of_t * old_count,
+            size_of_t * new_count,
+        ) as *mut T
+    }
 }
 
 /// Safety:
 /// - ptr is a block of memory currently allocated via this allocator.
 /// - layout is the same layout that was used to allocate this block of memory.
-unsafe fn reallocate(pointer: *mut u8, _old_size: usize, new_size: usize) -> *mut u8 {
+unsafe fn reallocate(vm: &mut Vm, pointer: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
+    vm.bytes_allocated = vm.bytes_allocated + new_size - old_size;
+    if new_size > old_size {
+        #[cfg(feature = "debug_stress_gc")]
+        collect_garbage(vm);
+
+        if vm.bytes_allocated > vm.next_gc {
+            collect_garbage(vm);
+        }
+    }
+
     if new_size == 0 {
-        // Safety:
-        // - safety of dealloc is ensured by the caller
-        unsafe { dealloc(pointer, Layout::new::<u8>()) };
+        // empty arrays (e.g. the characters of "") are never allocated
+        if !pointer.is_null() {
+            // Safety:
+            // - safety of dealloc is ensured by the caller
+            unsafe { dealloc(pointer, Layout::new::<u8>()) };
+        }
         return null_mut();
     }
 
@@ -48,7 +90,7 @@ unsafe fn reallocate(pointer: *mut u8, _old_size: usize, new_size: usize) -> *mu
     };
 
     if result.is_null() {
-        dbg!("couldn't realloc");
+        eprintln!("couldn't realloc");
         exit(1);
     }
 
@@ -58,6 +100,340 @@ unsafe fn reallocate(pointer: *mut u8, _old_size: usize, new_size: usize) -> *mu
 /// Safety:
 /// - ptr is a block of memory currently allocated via this allocator.
 /// - layout is the same layout that was used to allocate this block of memory.
-pub unsafe fn free_array<T>(pointer: *mut T, old_count: usize) {
-    reallocate(pointer as *mut u8, size_of::<T>() * old_count, 0);
+pub unsafe fn free_array<T>(vm: &mut Vm, pointer: *mut T, old_count: usize) {
+    unsafe { reallocate(vm, pointer as *mut u8, size_of::<T>() * old_count, 0) };
+}
+
+/// Safety:
+/// - object was allocated with `allocate_object` and is not referenced anymore.
+unsafe fn free_object(vm: &mut Vm, object: *mut Obj) {
+    #[cfg(feature = "debug_log_gc")]
+    println!("{object:p} free type {:?}", unsafe { (*object).typee });
+
+    match unsafe { (*object).typee } {
+        ObjType::BoundMethod => unsafe {
+            free::<ObjBoundMethod>(vm, object as *mut ObjBoundMethod)
+        },
+        ObjType::Class => {
+            let class = object as *mut ObjClass;
+            unsafe { (*class).methods.free_table(vm) };
+            unsafe { free::<ObjClass>(vm, class) };
+        }
+        ObjType::Closure => {
+            let closure = object as *mut ObjClosure;
+            // the closure doesn't own the upvalues themselves, just the array
+            unsafe { free_array(vm, (*closure).upvalues, (*closure).upvalue_count) };
+            unsafe { free::<ObjClosure>(vm, closure) };
+        }
+        ObjType::Function => {
+            let function = object as *mut ObjFunction;
+            unsafe { (*function).chunk.free_chunk(vm) };
+            unsafe { free::<ObjFunction>(vm, function) };
+        }
+        ObjType::Instance => {
+            let instance = object as *mut ObjInstance;
+            unsafe { (*instance).fields.free_table(vm) };
+            unsafe { free::<ObjInstance>(vm, instance) };
+        }
+        ObjType::Native => unsafe { free::<ObjNative>(vm, object as *mut ObjNative) },
+        ObjType::String => {
+            let string = object as *mut ObjString;
+            unsafe { free_array::<u8>(vm, (*string).chars, (*string).length) };
+            unsafe { free::<ObjString>(vm, string) };
+        }
+        ObjType::Upvalue => unsafe { free::<ObjUpvalue>(vm, object as *mut ObjUpvalue) },
+    }
+}
+
+pub fn mark_object(vm: &mut Vm, object: *mut Obj) {
+    if object.is_null() || unsafe { (*object).is_marked } {
+        return;
+    }
+
+    #[cfg(feature = "debug_log_gc")]
+    println!("{object:p} mark {}", Value::obj(object));
+
+    unsafe { (*object).is_marked = true };
+
+    // the gray stack uses the system allocator directly, so growing it can't start another
+    // collection
+    if vm.gray_capacity < vm.gray_count + 1 {
+        let old_layout = Layout::array::<*mut Obj>(vm.gray_capacity).unwrap();
+        vm.gray_capacity = grow_capacity(vm.gray_capacity);
+        let new_layout = Layout::array::<*mut Obj>(vm.gray_capacity).unwrap();
+        vm.gray_stack = unsafe {
+            if vm.gray_stack.is_null() {
+                alloc(new_layout)
+            } else {
+                realloc(vm.gray_stack as *mut u8, old_layout, new_layout.size())
+            }
+        } as *mut *mut Obj;
+
+        if vm.gray_stack.is_null() {
+            eprintln!("couldn't grow gray stack");
+            exit(1);
+        }
+    }
+
+    unsafe { *vm.gray_stack.add(vm.gray_count) = object };
+    vm.gray_count += 1;
+}
+
+pub fn mark_value(vm: &mut Vm, value: Value) {
+    if is_obj(value) {
+        mark_object(vm, as_obj(value));
+    }
+}
+
+fn mark_array(vm: &mut Vm, array: &ValueArray) {
+    for i in 0..array.count {
+        mark_value(vm, unsafe { *array.values.add(i) });
+    }
+}
+
+pub fn mark_table(vm: &mut Vm, table: &Table) {
+    for i in 0..table.capacity {
+        let entry = unsafe { *table.entries.add(i) };
+        mark_object(vm, entry.key as *mut Obj);
+        mark_value(vm, entry.value);
+    }
+}
+
+/// Mark everything the object references.
+fn blacken_object(vm: &mut Vm, object: *mut Obj) {
+    #[cfg(feature = "debug_log_gc")]
+    println!("{object:p} blacken {}", Value::obj(object));
+
+    match unsafe { (*object).typee } {
+        ObjType::BoundMethod => {
+            let bound = unsafe { &*(object as *mut ObjBoundMethod) };
+            mark_value(vm, bound.receiver);
+            mark_object(vm, bound.method as *mut Obj);
+        }
+        ObjType::Class => {
+            let class = unsafe { &*(object as *mut ObjClass) };
+            mark_object(vm, class.name as *mut Obj);
+            mark_table(vm, &class.methods);
+        }
+        ObjType::Closure => {
+            let closure = unsafe { &*(object as *mut ObjClosure) };
+            mark_object(vm, closure.function as *mut Obj);
+            for i in 0..closure.upvalue_count {
+                mark_object(vm, unsafe { *closure.upvalues.add(i) } as *mut Obj);
+            }
+        }
+        ObjType::Function => {
+            let function = unsafe { &*(object as *mut ObjFunction) };
+            mark_object(vm, function.name as *mut Obj);
+            mark_object(vm, function.source as *mut Obj);
+            mark_array(vm, &function.chunk.constants);
+        }
+        ObjType::Instance => {
+            let instance = unsafe { &*(object as *mut ObjInstance) };
+            mark_object(vm, instance.class as *mut Obj);
+            mark_table(vm, &instance.fields);
+        }
+        ObjType::Upvalue => mark_value(vm, unsafe { (*(object as *mut ObjUpvalue)).closed }),
+        ObjType::Native | ObjType::String => {}
+    }
+}
+
+fn mark_roots(vm: &mut Vm) {
+    for slot in 0..vm.stack_top {
+        mark_value(vm, vm.stack[slot]);
+    }
+
+    for i in 0..vm.frame_count {
+        mark_object(vm, vm.frames[i].closure as *mut Obj);
+    }
+
+    let mut upvalue = vm.open_upvalues;
+    while !upvalue.is_null() {
+        mark_object(vm, upvalue as *mut Obj);
+        upvalue = unsafe { (*upvalue).next };
+    }
+
+    let globals = &raw const vm.globals;
+    mark_table(vm, unsafe { &*globals });
+    mark_object(vm, vm.init_string as *mut Obj);
+    for value in vm.roots.held() {
+        mark_value(vm, value);
+    }
+    mark_compiler_roots(vm);
+}
+
+fn trace_references(vm: &mut Vm) {
+    while vm.gray_count > 0 {
+        vm.gray_count -= 1;
+        let object = unsafe { *vm.gray_stack.add(vm.gray_count) };
+        blacken_object(vm, object);
+    }
+}
+
+/// Free every object that wasn't marked and clear the mark on the rest for the next collection.
+fn sweep(vm: &mut Vm) {
+    let mut previous: *mut Obj = null_mut();
+    let mut object = vm.objects;
+    while !object.is_null() {
+        unsafe {
+            if (*object).is_marked {
+                (*object).is_marked = false;
+                previous = object;
+                object = (*object).next;
+            } else {
+                let unreached = object;
+                object = (*object).next;
+                if previous.is_null() {
+                    vm.objects = object;
+                } else {
+                    (*previous).next = object;
+                }
+
+                free_object(vm, unreached);
+            }
+        }
+    }
+}
+
+pub fn collect_garbage(vm: &mut Vm) {
+    #[cfg(feature = "debug_log_gc")]
+    println!("-- gc begin");
+    #[cfg(feature = "debug_log_gc")]
+    let before = vm.bytes_allocated;
+
+    mark_roots(vm);
+    trace_references(vm);
+    // interned strings are weak references, so remove them before their memory is freed
+    let strings = &raw mut vm.strings;
+    unsafe { (*strings).remove_white() };
+    sweep(vm);
+
+    vm.next_gc = vm.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+    #[cfg(feature = "debug_log_gc")]
+    {
+        println!("-- gc end");
+        let after = vm.bytes_allocated;
+        println!(
+            "   collected {} bytes (from {before} to {after}) next at {}",
+            before - after,
+            vm.next_gc
+        );
+    }
+}
+
+pub fn free_objects(vm: &mut Vm) {
+    let mut object = vm.objects;
+    while !object.is_null() {
+        let next = unsafe { (*object).next };
+        // Safety: every object in the list was allocated with `allocate_object` and the vm is
+        // being torn down, so nothing references it anymore.
+        unsafe { free_object(vm, object) };
+        object = next;
+    }
+    vm.objects = null_mut();
+
+    if !vm.gray_stack.is_null() {
+        unsafe {
+            dealloc(
+                vm.gray_stack as *mut u8,
+                Layout::array::<*mut Obj>(vm.gray_capacity).unwrap(),
+            )
+        };
+    }
+    vm.gray_stack = null_mut();
+    vm.gray_capacity = 0;
+    vm.gray_count = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::collect_garbage;
+    use crate::{
+        object::{Obj, ObjString, copy_string, hash_string},
+        value::Value,
+        vm::Vm,
+    };
+
+    fn object_count(vm: &Vm) -> usize {
+        let mut count = 0;
+        let mut object = vm.objects;
+        while !object.is_null() {
+            count += 1;
+            object = unsafe { (*object).next };
+        }
+        count
+    }
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut vm = Vm::new();
+        collect_garbage(&mut vm);
+        let objects = object_count(&vm);
+
+        copy_string(&mut vm, "garbage");
+        assert_eq!(object_count(&vm), objects + 1);
+        let bytes = vm.bytes_allocated;
+        collect_garbage(&mut vm);
+
+        assert_eq!(object_count(&vm), objects);
+        assert_eq!(
+            vm.bytes_allocated,
+            bytes - size_of::<ObjString>() - "garbage".len()
+        );
+        // the intern table doesn't keep strings alive
+        assert!(
+            vm.strings
+                .find_string("garbage", hash_string("garbage"))
+                .is_none()
+        );
+        assert_eq!(vm.next_gc, vm.bytes_allocated * 2);
+    }
+
+    #[test]
+    fn scripts_live_until_dropped() {
+        let mut vm = Vm::new();
+        collect_garbage(&mut vm);
+        let objects = object_count(&vm);
+
+        let script = vm.compile("1 + 2;").unwrap();
+        let copy = script.clone();
+        drop(script);
+        collect_garbage(&mut vm);
+        assert!(object_count(&vm) > objects);
+        assert_eq!(vm.execute(&copy).unwrap().as_number(), Some(3.0));
+
+        drop(copy);
+        collect_garbage(&mut vm);
+        assert_eq!(object_count(&vm), objects);
+    }
+
+    #[test]
+    fn reachable_objects_survive() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "class Box { init(value) { this.value = value; } }
+            fun makeGetter() {
+                var captured = \"captured\" + \" string\";
+                fun get() { return captured; }
+                return get;
+            }
+            var getter = makeGetter();
+            var box = Box(\"boxed\" + \" string\");",
+        )
+        .unwrap();
+        let on_stack = copy_string(&mut vm, "on the stack");
+        vm.push(Value::obj(on_stack as *mut Obj));
+
+        collect_garbage(&mut vm);
+
+        assert!(
+            vm.strings
+                .find_string("on the stack", hash_string("on the stack"))
+                .is_some()
+        );
+        let result = vm.interpret("getter() + \" and \" + box.value;").unwrap();
+        assert_eq!(result.to_string(), "captured string and boxed string");
+        vm.pop();
+    }
 }
@@ -0,0 +1,95 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    object::{BuiltinFn, Obj, ObjType, as_string, copy_string, is_string, obj_type},
+    value::{Value, is_bool, is_nil, is_number},
+    vm::Vm,
+};
+
+/// Natives defined as globals in every vm.
+pub const BUILTINS: [(&str, BuiltinFn); 5] = [
+    ("clock", clock_native),
+    ("str", str_native),
+    ("num", num_native),
+    ("len", len_native),
+    ("type", type_native),
+];
+
+fn check_arity(args: &[Value], arity: usize) -> Result<(), String> {
+    if args.len() != arity {
+        return Err(format!(
+            "Expected {arity} arguments but got {}.",
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Seconds since the unix epoch.
+fn clock_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 0)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| err.to_string())?;
+    Ok(Value::number(now.as_secs_f64()))
+}
+
+/// Converts any value to the string `print` would show for it.
+fn str_native(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 1)?;
+    if is_string(args[0]) {
+        return Ok(args[0]);
+    }
+    let string = copy_string(vm, &args[0].to_string());
+    Ok(Value::obj(string as *mut Obj))
+}
+
+/// Parses a string into a number. Numbers are returned unchanged.
+fn num_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 1)?;
+    match args[0] {
+        value if is_number(value) => Ok(value),
+        value if is_string(value) => {
+            let chars = unsafe { (*as_string(value)).as_str() };
+            chars
+                .trim()
+                .parse::<f64>()
+                .map(Value::number)
+                .map_err(|_| format!("Can't convert '{chars}' to a number."))
+        }
+        _ => Err("Argument must be a number or a string.".to_string()),
+    }
+}
+
+/// Number of characters in a string.
+fn len_native(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 1)?;
+    if !is_string(args[0]) {
+        return Err("Argument must be a string.".to_string());
+    }
+    let chars = unsafe { (*as_string(args[0])).as_str() };
+    Ok(Value::number(chars.chars().count() as f64))
+}
+
+/// Name of the value's type as a string.
+fn type_native(vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    check_arity(args, 1)?;
+    let value = args[0];
+    let name = if is_number(value) {
+        "number"
+    } else if is_bool(value) {
+        "bool"
+    } else if is_nil(value) {
+        "nil"
+    } else {
+        match obj_type(value) {
+            ObjType::Class => "class",
+            ObjType::BoundMethod | ObjType::Closure | ObjType::Function => "function",
+            ObjType::Instance => "instance",
+            ObjType::Native => "native",
+            ObjType::String => "string",
+            ObjType::Upvalue => "upvalue",
+        }
+    };
+    Ok(Value::obj(copy_string(vm, name) as *mut Obj))
+}
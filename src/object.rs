@@ -0,0 +1,378 @@
+use std::{
+    fmt::Display,
+    ptr::{copy_nonoverlapping, null_mut},
+};
+
+use crate::{
+    chunk::Chunk,
+    handle,
+    memory::{allocate, free_array},
+    table::Table,
+    value::{Value, as_obj, is_obj},
+    vm::Vm,
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ObjType {
+    BoundMethod,
+    Class,
+    Closure,
+    Function,
+    Instance,
+    Native,
+    String,
+    Upvalue,
+}
+
+/// Header shared by every heap allocated object. Each concrete object is `repr(C)` with the header
+/// as its first field, so a `*mut Obj` can be cast to the concrete type once `typee` is checked.
+#[repr(C)]
+pub struct Obj {
+    pub(crate) typee: ObjType,
+    /// reachable during the current garbage collection
+    pub(crate) is_marked: bool,
+    /// intrusive list of every allocated object, so the vm can free them
+    pub(crate) next: *mut Obj,
+}
+
+#[repr(C)]
+pub struct ObjString {
+    pub(crate) obj: Obj,
+    /// length in bytes
+    pub(crate) length: usize,
+    /// utf8 bytes, not null terminated
+    pub(crate) chars: *mut u8,
+    pub(crate) hash: u32,
+}
+
+#[repr(C)]
+pub struct ObjFunction {
+    pub(crate) obj: Obj,
+    pub(crate) arity: usize,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+    /// null for the top level script
+    pub(crate) name: *mut ObjString,
+    /// whole source of the script the function is part of, so runtime errors can quote it
+    pub(crate) source: *mut ObjString,
+}
+
+/// Signature of functions implemented in rust. Returning an error raises a runtime error.
+///
+/// A native can run other scripts through its `&mut Vm`, errors in them are returned to the
+/// native and leave the calling script running.
+pub type NativeFn = fn(&mut Vm, &[handle::Value]) -> Result<handle::Value, String>;
+
+/// Signature of the builtin natives, which work on the vm's values directly.
+pub(crate) type BuiltinFn = fn(&mut Vm, &[Value]) -> Result<Value, String>;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Native {
+    Builtin(BuiltinFn),
+    /// registered by the host, its arguments and result go through handles
+    Host(NativeFn),
+}
+
+#[repr(C)]
+pub struct ObjNative {
+    pub(crate) obj: Obj,
+    pub(crate) function: Native,
+}
+
+/// A variable captured by a closure. While the variable is still on the stack the upvalue is open
+/// and `location` points at the stack slot. Once the variable goes out of scope it is moved into
+/// `closed` and `location` points there instead.
+#[repr(C)]
+pub struct ObjUpvalue {
+    pub(crate) obj: Obj,
+    pub(crate) location: *mut Value,
+    pub(crate) closed: Value,
+    /// next open upvalue, sorted by stack slot from the top of the stack down
+    pub(crate) next: *mut ObjUpvalue,
+}
+
+#[repr(C)]
+pub struct ObjClosure {
+    pub(crate) obj: Obj,
+    pub(crate) function: *mut ObjFunction,
+    /// array of `upvalue_count` upvalues
+    pub(crate) upvalues: *mut *mut ObjUpvalue,
+    pub(crate) upvalue_count: usize,
+}
+
+#[repr(C)]
+pub struct ObjClass {
+    pub(crate) obj: Obj,
+    pub(crate) name: *mut ObjString,
+    pub(crate) methods: Table,
+}
+
+#[repr(C)]
+pub struct ObjInstance {
+    pub(crate) obj: Obj,
+    pub(crate) class: *mut ObjClass,
+    pub(crate) fields: Table,
+}
+
+/// A method accessed on an instance, remembering the instance so it can be called later.
+#[repr(C)]
+pub struct ObjBoundMethod {
+    pub(crate) obj: Obj,
+    pub(crate) receiver: Value,
+    pub(crate) method: *mut ObjClosure,
+}
+
+impl ObjString {
+    pub fn as_str(&self) -> &str {
+        if self.length == 0 {
+            return "";
+        }
+        // Safety: chars is always built from a `&str` of `length` bytes.
+        unsafe {
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(self.chars, self.length))
+        }
+    }
+}
+
+/// Safety:
+/// - T must be `repr(C)` with an `Obj` as its first field.
+/// - the fields after the header are uninitialized and must be written by the caller.
+unsafe fn allocate_object<T>(vm: &mut Vm, typee: ObjType) -> *mut T {
+    let object = unsafe { allocate::<T>(vm, 1) } as *mut Obj;
+    unsafe {
+        (*object).typee = typee;
+        (*object).is_marked = false;
+        (*object).next = vm.objects;
+    }
+    vm.objects = object;
+
+    #[cfg(feature = "debug_log_gc")]
+    println!("{object:p} allocate {} for {typee:?}", size_of::<T>());
+
+    object as *mut T
+}
+
+pub fn new_bound_method(
+    vm: &mut Vm,
+    receiver: Value,
+    method: *mut ObjClosure,
+) -> *mut ObjBoundMethod {
+    // Safety: ObjBoundMethod is repr(C) with Obj as its first field and we initialize the rest
+    // below.
+    let bound = unsafe { allocate_object::<ObjBoundMethod>(vm, ObjType::BoundMethod) };
+    unsafe {
+        (*bound).receiver = receiver;
+        (*bound).method = method;
+    }
+    bound
+}
+
+pub fn new_class(vm: &mut Vm, name: *mut ObjString) -> *mut ObjClass {
+    // Safety: ObjClass is repr(C) with Obj as its first field and we initialize the rest below.
+    let class = unsafe { allocate_object::<ObjClass>(vm, ObjType::Class) };
+    unsafe {
+        (*class).name = name;
+        (&raw mut (*class).methods).write(Table::new());
+    }
+    class
+}
+
+pub fn new_closure(vm: &mut Vm, function: *mut ObjFunction) -> *mut ObjClosure {
+    let upvalue_count = unsafe { (*function).upvalue_count };
+    let upvalues = unsafe { allocate::<*mut ObjUpvalue>(vm, upvalue_count) };
+    for i in 0..upvalue_count {
+        unsafe { *upvalues.add(i) = null_mut() };
+    }
+
+    // Safety: ObjClosure is repr(C) with Obj as its first field and we initialize the rest below.
+    let closure = unsafe { allocate_object::<ObjClosure>(vm, ObjType::Closure) };
+    unsafe {
+        (*closure).function = function;
+        (*closure).upvalues = upvalues;
+        (*closure).upvalue_count = upvalue_count;
+    }
+    closure
+}
+
+pub fn new_function(vm: &mut Vm) -> *mut ObjFunction {
+    // Safety: ObjFunction is repr(C) with Obj as its first field and we initialize the rest below.
+    let function = unsafe { allocate_object::<ObjFunction>(vm, ObjType::Function) };
+    unsafe {
+        (*function).arity = 0;
+        (*function).upvalue_count = 0;
+        (*function).name = null_mut();
+        (*function).source = null_mut();
+        (&raw mut (*function).chunk).write(Chunk::new());
+    }
+    function
+}
+
+pub fn new_instance(vm: &mut Vm, class: *mut ObjClass) -> *mut ObjInstance {
+    // Safety: ObjInstance is repr(C) with Obj as its first field and we initialize the rest below.
+    let instance = unsafe { allocate_object::<ObjInstance>(vm, ObjType::Instance) };
+    unsafe {
+        (*instance).class = class;
+        (&raw mut (*instance).fields).write(Table::new());
+    }
+    instance
+}
+
+pub(crate) fn new_native(vm: &mut Vm, function: Native) -> *mut ObjNative {
+    // Safety: ObjNative is repr(C) with Obj as its first field and we initialize the rest below.
+    let native = unsafe { allocate_object::<ObjNative>(vm, ObjType::Native) };
+    unsafe { (*native).function = function };
+    native
+}
+
+pub fn new_upvalue(vm: &mut Vm, slot: *mut Value) -> *mut ObjUpvalue {
+    // Safety: ObjUpvalue is repr(C) with Obj as its first field and we initialize the rest below.
+    let upvalue = unsafe { allocate_object::<ObjUpvalue>(vm, ObjType::Upvalue) };
+    unsafe {
+        (*upvalue).location = slot;
+        (*upvalue).closed = Value::NIL;
+        (*upvalue).next = null_mut();
+    }
+    upvalue
+}
+
+fn allocate_string(vm: &mut Vm, chars: *mut u8, length: usize, hash: u32) -> *mut ObjString {
+    // Safety: ObjString is repr(C) with Obj as its first field and we initialize the rest below.
+    let string = unsafe { allocate_object::<ObjString>(vm, ObjType::String) };
+    unsafe {
+        (*string).length = length;
+        (*string).chars = chars;
+        (*string).hash = hash;
+    }
+    // every string is interned, so the table is used as a set. The string is kept on the stack
+    // while inserting, since growing the table can trigger a collection.
+    vm.push(Value::obj(string as *mut Obj));
+    let strings = &raw mut vm.strings;
+    unsafe { (*strings).set(vm, string, Value::NIL) };
+    vm.pop();
+    string
+}
+
+/// FNV-1a
+pub(crate) fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+/// Create a string object that takes ownership of `chars`.
+///
+/// Safety:
+/// - chars was allocated with `allocate::<u8>(length)` and holds valid utf8.
+pub unsafe fn take_string(vm: &mut Vm, chars: *mut u8, length: usize) -> *mut ObjString {
+    let str_value = if length == 0 {
+        ""
+    } else {
+        unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(chars, length)) }
+    };
+    let hash = hash_string(str_value);
+    if let Some(interned) = vm.strings.find_string(str_value, hash) {
+        unsafe { free_array(vm, chars, length) };
+        return interned;
+    }
+
+    allocate_string(vm, chars, length, hash)
+}
+
+/// Create a string object holding a copy of `chars`.
+pub fn copy_string(vm: &mut Vm, chars: &str) -> *mut ObjString {
+    let hash = hash_string(chars);
+    if let Some(interned) = vm.strings.find_string(chars, hash) {
+        return interned;
+    }
+
+    let length = chars.len();
+    let heap_chars = unsafe { allocate::<u8>(vm, length) };
+    if length > 0 {
+        // Safety: heap_chars was just allocated with room for `length` bytes.
+        unsafe { copy_nonoverlapping(chars.as_ptr(), heap_chars, length) };
+    }
+    allocate_string(vm, heap_chars, length, hash)
+}
+
+pub fn obj_type(value: Value) -> ObjType {
+    unsafe { (*as_obj(value)).typee }
+}
+
+fn is_obj_type(value: Value, typee: ObjType) -> bool {
+    is_obj(value) && obj_type(value) == typee
+}
+
+pub fn is_string(value: Value) -> bool {
+    is_obj_type(value, ObjType::String)
+}
+
+pub fn as_string(value: Value) -> *mut ObjString {
+    as_obj(value) as *mut ObjString
+}
+
+pub fn is_instance(value: Value) -> bool {
+    is_obj_type(value, ObjType::Instance)
+}
+
+pub fn is_class(value: Value) -> bool {
+    is_obj_type(value, ObjType::Class)
+}
+
+pub fn as_bound_method(value: Value) -> *mut ObjBoundMethod {
+    as_obj(value) as *mut ObjBoundMethod
+}
+
+pub fn as_class(value: Value) -> *mut ObjClass {
+    as_obj(value) as *mut ObjClass
+}
+
+pub fn as_instance(value: Value) -> *mut ObjInstance {
+    as_obj(value) as *mut ObjInstance
+}
+
+pub fn as_closure(value: Value) -> *mut ObjClosure {
+    as_obj(value) as *mut ObjClosure
+}
+
+pub fn as_function(value: Value) -> *mut ObjFunction {
+    as_obj(value) as *mut ObjFunction
+}
+
+pub(crate) fn as_native(value: Value) -> Native {
+    unsafe { (*(as_obj(value) as *mut ObjNative)).function }
+}
+
+pub(crate) struct ObjDisplay(pub(crate) *mut Obj);
+
+impl Display for ObjDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match unsafe { (*self.0).typee } {
+            ObjType::BoundMethod => {
+                let method = unsafe { (*(self.0 as *mut ObjBoundMethod)).method };
+                ObjDisplay(method as *mut Obj).fmt(f)
+            }
+            ObjType::Class => unsafe { (*(*(self.0 as *mut ObjClass)).name).as_str() }.fmt(f),
+            ObjType::Closure => {
+                let function = unsafe { (*(self.0 as *mut ObjClosure)).function };
+                ObjDisplay(function as *mut Obj).fmt(f)
+            }
+            ObjType::Function => {
+                let function = unsafe { &*(self.0 as *mut ObjFunction) };
+                if function.name.is_null() {
+                    return f.write_str("<script>");
+                }
+                write!(f, "<fn {}>", unsafe { (*function.name).as_str() })
+            }
+            ObjType::Instance => {
+                let class = unsafe { (*(self.0 as *mut ObjInstance)).class };
+                write!(f, "{} instance", unsafe { (*(*class).name).as_str() })
+            }
+            ObjType::Native => f.write_str("<native fn>"),
+            ObjType::String => unsafe { &*(self.0 as *mut ObjString) }.as_str().fmt(f),
+            ObjType::Upvalue => f.write_str("upvalue"),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use TokenType::*;
+
+use crate::diagnostic::Span;
 use std::{fmt::Debug, iter::Peekable, ops::Coroutine, str::CharIndices};
 
 // Note: Converting method in book to use a coroutine, because the pointer based approach seems like
@@ -8,57 +10,102 @@ pub fn scan(source: &str) -> impl Coroutine<Return = (), Yield = Token> + use<'_
     || {
         let mut current_line = 1;
         let mut char_indices = source.char_indices().peekable();
+        // the column of the previous token, so finding the next one only counts the characters
+        // in between
+        let mut column = Column {
+            line: 1,
+            pos: 0,
+            column: 1,
+        };
         while let Some((pos, ch)) = char_indices.next() {
-            match ch {
-                '(' => yield Token::single(LeftParen, pos, current_line),
-                ')' => yield Token::single(RightParen, pos, current_line),
-                '{' => yield Token::single(LeftBrace, pos, current_line),
-                '}' => yield Token::single(RightBrace, pos, current_line),
-                ';' => yield Token::single(Semicolon, pos, current_line),
-                ',' => yield Token::single(Comma, pos, current_line),
-                '.' => yield Token::single(Dot, pos, current_line),
-                '-' => yield Token::single(Minus, pos, current_line),
-                '+' => yield Token::single(Plus, pos, current_line),
-                '*' => yield Token::single(Star, pos, current_line),
+            let line = current_line;
+            let token = match ch {
+                '(' => Token::single(LeftParen, pos),
+                ')' => Token::single(RightParen, pos),
+                '{' => Token::single(LeftBrace, pos),
+                '}' => Token::single(RightBrace, pos),
+                ';' => Token::single(Semicolon, pos),
+                ',' => Token::single(Comma, pos),
+                '.' => Token::single(Dot, pos),
+                '-' => Token::single(Minus, pos),
+                '+' => Token::single(Plus, pos),
+                '*' => Token::single(Star, pos),
                 '/' => match char_indices.next_if_eq(&(pos + 1, '/')) {
                     Some(_slash) => {
                         for (_pos, ch) in char_indices.by_ref() {
                             if ch == '\n' {
+                                current_line += 1;
                                 break;
                             }
                         }
+                        continue;
                     }
-                    None => yield Token::single(Slash, pos, current_line),
+                    None => Token::single(Slash, pos),
                 },
 
-                '!' => match char_indices.next_if_eq(&(pos + 1, '-')) {
-                    Some(_equals) => yield Token::multiple(BangEqual, pos, 2, current_line),
-                    None => yield Token::single(Bang, pos, current_line),
+                '!' => match char_indices.next_if_eq(&(pos + 1, '=')) {
+                    Some(_equals) => Token::multiple(BangEqual, pos, 2),
+                    None => Token::single(Bang, pos),
                 },
                 '=' => match char_indices.next_if_eq(&(pos + 1, '=')) {
-                    Some(_equals) => yield Token::multiple(EqualEqual, pos, 2, current_line),
-                    None => yield Token::single(Equal, pos, current_line),
+                    Some(_equals) => Token::multiple(EqualEqual, pos, 2),
+                    None => Token::single(Equal, pos),
                 },
                 '<' => match char_indices.next_if_eq(&(pos + 1, '=')) {
-                    Some(_equals) => yield Token::multiple(LessEqual, pos, 2, current_line),
-                    None => yield Token::single(Less, pos, current_line),
+                    Some(_equals) => Token::multiple(LessEqual, pos, 2),
+                    None => Token::single(Less, pos),
                 },
                 '>' => match char_indices.next_if_eq(&(pos + 1, '=')) {
-                    Some(_equals) => yield Token::multiple(GreaterEqual, pos, 2, current_line),
-                    None => yield Token::single(Greater, pos, current_line),
+                    Some(_equals) => Token::multiple(GreaterEqual, pos, 2),
+                    None => Token::single(Greater, pos),
                 },
                 // skip whitespace
-                ' ' | '\r' | '\t' => {}
-                '\n' => current_line += 1,
-                '0'..='9' => yield number(&mut char_indices, pos, &mut current_line),
-                '"' => yield string(&mut char_indices, pos, &mut current_line),
-                'a'..='z' | 'A'..='Z' => {
-                    yield identifier(&mut char_indices, source, pos, &mut current_line)
+                ' ' | '\r' | '\t' => continue,
+                '\n' => {
+                    current_line += 1;
+                    continue;
                 }
-                _ => yield Token::error("Unexpected character.", current_line),
-            }
+                '0'..='9' => number(&mut char_indices, source, pos),
+                '"' => string(&mut char_indices, pos, &mut current_line),
+                'r' if matches!(char_indices.peek(), Some((_, '"' | '#'))) => {
+                    raw_string(&mut char_indices, pos, &mut current_line)
+                }
+                'a'..='z' | 'A'..='Z' | '_' => identifier(&mut char_indices, source, pos),
+                _ => Token::error("Unexpected character.", pos, ch.len_utf8()),
+            };
+            // an invalid escape is reported where the escape is, possibly lines into its string
+            let start = token.start();
+            let line = line + source[pos..start].matches('\n').count();
+            yield token.at(line, column.advance(source, line, start));
         }
-        yield Token::single(Eof, source.len(), current_line);
+        let eof = Token::multiple(Eof, source.len(), 0);
+        yield eof.at(
+            current_line,
+            column.advance(source, current_line, source.len()),
+        );
+    }
+}
+
+/// Column of a position in the source.
+struct Column {
+    line: usize,
+    pos: usize,
+    /// 1 based, counted in characters
+    column: usize,
+}
+
+impl Column {
+    /// Move forward to `pos` on `line`, returning its column.
+    fn advance(&mut self, source: &str, line: usize, pos: usize) -> usize {
+        if line != self.line {
+            // count from the start of the new line
+            self.line = line;
+            self.pos = source[..pos].rfind('\n').map_or(0, |newline| newline + 1);
+            self.column = 1;
+        }
+        self.column += source[self.pos..pos].chars().count();
+        self.pos = pos;
+        self.column
     }
 }
 
@@ -67,58 +114,197 @@ fn string(
     start: usize,
     current_line: &mut usize,
 ) -> Token {
-    let mut length = 0;
-
-    let mut double_quote_found = false;
-    for (_pos, ch) in char_indices.by_ref() {
+    // length in bytes including both quotes
+    let mut length = None;
+    // end of the last character read, to cover the rest of the source if the string isn't closed
+    let mut end = start + 1;
+    // the first invalid escape, reported once the whole string has been skipped
+    let mut invalid_escape = None;
+    while let Some((pos, ch)) = char_indices.next() {
+        end = pos + ch.len_utf8();
         if ch == '"' {
-            double_quote_found = true;
+            length = Some(end - start);
             break;
-        } else {
-            if ch == '\n' {
-                *current_line += 1;
-            }
-            length += 1;
+        } else if ch == '\n' {
+            *current_line += 1;
+        } else if ch == '\\'
+            && let Err(message) = escape(char_indices)
+        {
+            let escape_end = char_indices.peek().map_or(end, |&(next, _)| next);
+            invalid_escape.get_or_insert(Token::error(message, pos, escape_end - pos));
         }
     }
 
-    if !double_quote_found {
-        return Token::error("Unterminated string.", *current_line);
+    let Some(length) = length else {
+        return Token::error("Unterminated string.", start, end - start);
+    };
+    if let Some(error) = invalid_escape {
+        return error;
     }
 
-    Token::multiple(TokenType::String, start, length, *current_line)
+    Token::multiple(TokenType::String, start, length)
 }
 
-fn number(
+/// Read the escape sequence after a `\`, returning the character it stands for.
+///
+/// The scanner uses this to check escapes, the compiler to decode them.
+pub fn escape(chars: &mut Peekable<CharIndices<'_>>) -> Result<char, &'static str> {
+    // leave a newline for the caller, so it's still counted as a new line
+    let Some((_, ch)) = chars.next_if(|&(_, ch)| ch != '\n') else {
+        return Err("Invalid escape sequence.");
+    };
+    match ch {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        '\\' => Ok('\\'),
+        '"' => Ok('"'),
+        // a unicode code point written as 1 to 6 hex digits, like \u{1F600}
+        'u' => {
+            if chars.next_if(|&(_, ch)| ch == '{').is_none() {
+                return Err("Invalid unicode escape.");
+            }
+            let mut code_point = 0;
+            let mut digits = 0;
+            while let Some((_, digit)) = chars.next_if(|&(_, ch)| ch.is_ascii_hexdigit()) {
+                digits += 1;
+                if digits <= 6 {
+                    code_point = code_point * 16 + digit.to_digit(16).unwrap();
+                }
+            }
+            if digits == 0 || digits > 6 || chars.next_if(|&(_, ch)| ch == '}').is_none() {
+                return Err("Invalid unicode escape.");
+            }
+            char::from_u32(code_point).ok_or("Invalid unicode escape.")
+        }
+        _ => Err("Invalid escape sequence."),
+    }
+}
+
+/// A string that doesn't process escapes, like `r"C:\dir"`. Any number of `#` can go between
+/// the `r` and the quotes, to allow quotes inside: `r#"say "hi""#`.
+fn raw_string(
     char_indices: &mut Peekable<CharIndices<'_>>,
     start: usize,
     current_line: &mut usize,
 ) -> Token {
-    let mut length = 1;
-    while let Some((_pos, ch)) = char_indices.peek() {
-        match ch {
-            '0'..='9' | '.' => {
-                length += 1;
-                // only advance the iterator if we find a number character
-                char_indices.next();
+    let mut hashes = 0;
+    let mut end = start + 1;
+    while let Some((pos, _)) = char_indices.next_if(|&(_, ch)| ch == '#') {
+        hashes += 1;
+        end = pos + 1;
+    }
+    let Some((pos, _)) = char_indices.next_if(|&(_, ch)| ch == '"') else {
+        return Token::error("Expect '\"' to start raw string.", start, end - start);
+    };
+    end = pos + 1;
+
+    while let Some((pos, ch)) = char_indices.next() {
+        end = pos + ch.len_utf8();
+        if ch == '\n' {
+            *current_line += 1;
+        } else if ch == '"' {
+            // only a quote followed by as many hashes as the opening one ends the string
+            let mut closing = 0;
+            while closing < hashes && char_indices.next_if(|&(_, ch)| ch == '#').is_some() {
+                closing += 1;
+                end += 1;
+            }
+            if closing == hashes {
+                return Token::multiple(TokenType::String, start, end - start);
             }
-            _ => break,
         }
     }
 
-    Token::multiple(TokenType::Number, start, length, *current_line)
+    Token::error("Unterminated string.", start, end - start)
 }
 
-fn identifier(
-    char_indices: &mut Peekable<CharIndices<'_>>,
-    source: &str,
-    start: usize,
-    current_line: &mut usize,
-) -> Token {
+fn number(char_indices: &mut Peekable<CharIndices<'_>>, source: &str, start: usize) -> Token {
+    // take everything that could belong to the literal, so a malformed one like `1.2.3` or
+    // `0xZZ` is a single error rather than several tokens
+    let radix_prefix = matches!(source.get(start..start + 2), Some("0x" | "0b" | "0o"));
+    let mut end = start + 1;
+    let mut previous = source[start..].chars().next().unwrap();
+    while let Some(&(pos, ch)) = char_indices.peek() {
+        let takes = match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
+            // a fraction needs a digit after the dot, `1.` is a number followed by a dot
+            '.' => {
+                let mut after = char_indices.clone();
+                after.next();
+                matches!(after.peek(), Some((_, '0'..='9')))
+            }
+            '+' | '-' => !radix_prefix && matches!(previous, 'e' | 'E'),
+            _ => false,
+        };
+        if !takes {
+            break;
+        }
+        char_indices.next();
+        end = pos + ch.len_utf8();
+        previous = ch;
+    }
+
+    if let Err(message) = parse_number(&source[start..end]) {
+        return Token::error(message, start, end - start);
+    }
+    Token::multiple(TokenType::Number, start, end - start)
+}
+
+/// The value of a number literal: decimal with an optional fraction and exponent like `1.5e-9`,
+/// or an integer with a `0x`, `0b` or `0o` prefix. Digits can be separated by `_`.
+///
+/// The scanner uses this to check literals, the compiler to evaluate them.
+pub fn parse_number(lexeme: &str) -> Result<f64, &'static str> {
+    let (radix, digits) = match lexeme.get(..2) {
+        Some("0x") => (16, &lexeme[2..]),
+        Some("0b") => (2, &lexeme[2..]),
+        Some("0o") => (8, &lexeme[2..]),
+        _ => (10, lexeme),
+    };
+
+    // separators only go between two digits
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, &ch) in chars.iter().enumerate() {
+        let is_digit = |neighbour: Option<&char>| neighbour.is_some_and(|ch| ch.is_digit(radix));
+        if ch == '_' && !(i > 0 && is_digit(chars.get(i - 1)) && is_digit(chars.get(i + 1))) {
+            return Err("Misplaced '_' in number.");
+        }
+    }
+    let digits = digits.replace('_', "");
+
+    if radix != 10 {
+        if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+            return Err("Invalid digit in number.");
+        }
+        return u64::from_str_radix(&digits, radix)
+            .map(|value| value as f64)
+            .map_err(|_| "Number is too large.");
+    }
+
+    // digits ('.' digits)? ([eE] [+-]? digits)?
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (digits.as_str(), None),
+    };
+    let all_digits =
+        |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    let valid_mantissa = match mantissa.split_once('.') {
+        Some((whole, fraction)) => all_digits(whole) && all_digits(fraction),
+        None => all_digits(mantissa),
+    };
+    let valid_exponent = exponent
+        .is_none_or(|exponent| all_digits(exponent.strip_prefix(['+', '-']).unwrap_or(exponent)));
+    if !valid_mantissa || !valid_exponent {
+        return Err("Invalid number literal.");
+    }
+    Ok(digits.parse().unwrap())
+}
+
+fn identifier(char_indices: &mut Peekable<CharIndices<'_>>, source: &str, start: usize) -> Token {
     let mut length = 1;
     while let Some((_pos, ch)) = char_indices.peek() {
         match ch {
-            'a'..='z' | 'A'..='Z' | '0'..='9' => {
+            'a'..='z' | 'A'..='Z' | '_' | '0'..='9' => {
                 char_indices.next();
                 length += 1;
             }
@@ -126,10 +312,13 @@ fn identifier(
         }
     }
 
-    Token::multiple(identifier_type(source, start), start, length, *current_line)
+    Token::multiple(identifier_type(source, start, length), start, length)
 }
 
-fn identifier_type(source: &str, start: usize) -> TokenType {
+fn identifier_type(source: &str, start: usize, length: usize) -> TokenType {
+    // only look at the identifier itself, so a keyword prefix like `fun` in `function` isn't
+    // mistaken for the keyword
+    let source = &source[..start + length];
     match source.get(start..=start).unwrap() {
         "a" => check_keyword(source, start + 1, 2, "nd", And),
         "c" => check_keyword(source, start + 1, 4, "lass", Class),
@@ -164,6 +353,10 @@ fn check_keyword(
     rest: &'static str,
     typee: TokenType,
 ) -> TokenType {
+    // the rest of the keyword has to run to the end of the identifier
+    if start + length != source.len() {
+        return Identifier;
+    }
     let Some(source_slice) = source.get(start..start + length) else {
         return Identifier;
     };
@@ -175,65 +368,93 @@ fn check_keyword(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Token {
     pub typee: TokenType,
     // TODO: change this to be a slice into the original string?
-    pub token_union: TokenUnion,
+    /// byte offset in the source
+    start: usize,
     pub length: usize,
+    /// line the token starts on
     pub line: usize,
-}
-
-pub union TokenUnion {
-    start: usize,
+    /// 1 based, counted in characters
+    pub column: usize,
+    /// what went wrong, only set for `TokenType::Error`
     message: &'static str,
 }
 
-impl Debug for TokenUnion {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Ok(())
-    }
-}
-
 impl Token {
     /// Token that is a single character
-    fn single(typee: TokenType, start: usize, line: usize) -> Token {
+    fn single(typee: TokenType, start: usize) -> Token {
+        Token::multiple(typee, start, 1)
+    }
+
+    fn multiple(typee: TokenType, start: usize, length: usize) -> Token {
         Token {
             typee,
-            token_union: TokenUnion { start },
-            length: 1,
+            start,
+            length,
+            line: 0,
+            column: 0,
+            message: "",
+        }
+    }
+
+    /// Set where the token starts.
+    fn at(self, line: usize, column: usize) -> Token {
+        Token {
             line,
+            column,
+            ..self
         }
     }
 
-    fn multiple(typee: TokenType, start: usize, length: usize, line: usize) -> Token {
+    /// Token that doesn't appear in the source, like the name of a function's reserved stack slot.
+    pub fn synthetic(typee: TokenType, line: usize) -> Token {
         Token {
             typee,
-            token_union: TokenUnion { start },
-            length,
+            start: 0,
+            length: 0,
             line,
+            column: 0,
+            message: "",
         }
     }
 
-    pub fn error(message: &'static str, line: usize) -> Token {
+    /// Token covering source the scanner couldn't make sense of.
+    pub fn error(message: &'static str, start: usize, length: usize) -> Token {
         Token {
             typee: Error,
-            token_union: TokenUnion { message },
-            length: message.len(),
-            line,
+            start,
+            length,
+            line: 0,
+            column: 0,
+            message,
+        }
+    }
+
+    /// Token running from the start of this one to the end of `end`, on this token's line.
+    pub fn through(self, end: &Token) -> Token {
+        Token {
+            length: end.start + end.length - self.start,
+            ..self
         }
     }
 
     pub fn start(&self) -> usize {
-        match self.typee {
-            Error => panic!("can't get start on TokenType::Error"),
-            _ => unsafe { self.token_union.start },
+        self.start
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            end: self.start + self.length,
         }
     }
 
     pub fn message(&self) -> &'static str {
         match self.typee {
-            Error => unsafe { self.token_union.message },
+            Error => self.message,
             _ => panic!("Can't get message on not TokenType::Error"),
         }
     }
@@ -282,3 +503,120 @@ pub enum TokenType {
     Error,
     Eof,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenType, parse_number, scan};
+
+    #[test]
+    fn tokens_know_their_line_and_column() {
+        let source = "var é = \"a\nb\";\n  print é;";
+        let tokens: Vec<_> = std::iter::from_coroutine(scan(source))
+            .map(|token| (token.typee, token.line, token.column))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Var, 1, 1),
+                (TokenType::Error, 1, 5),
+                (TokenType::Equal, 1, 7),
+                // multi line strings start on their first line
+                (TokenType::String, 1, 9),
+                (TokenType::Semicolon, 2, 3),
+                (TokenType::Print, 3, 3),
+                (TokenType::Error, 3, 9),
+                (TokenType::Semicolon, 3, 10),
+                (TokenType::Eof, 3, 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_point_at_the_escape() {
+        let tokens: Vec<_> = std::iter::from_coroutine(scan("print \"a\\qb\";\n\"\n  \\q\";"))
+            .map(|token| (token.typee, token.line, token.column, token.length))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Print, 1, 1, 5),
+                (TokenType::Error, 1, 9, 2),
+                (TokenType::Semicolon, 1, 13, 1),
+                (TokenType::Error, 3, 3, 2),
+                (TokenType::Semicolon, 3, 6, 1),
+                (TokenType::Eof, 3, 7, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_end_at_the_newline() {
+        let lines: Vec<_> = std::iter::from_coroutine(scan("a // one\n// two\nb"))
+            .map(|token| (token.typee, token.line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (TokenType::Identifier, 1),
+                (TokenType::Identifier, 3),
+                (TokenType::Eof, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn operators_match_their_second_character() {
+        let types: Vec<_> = std::iter::from_coroutine(scan("! != = == < <= > >= !-"))
+            .map(|token| token.typee)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                TokenType::Bang,
+                TokenType::BangEqual,
+                TokenType::Equal,
+                TokenType::EqualEqual,
+                TokenType::Less,
+                TokenType::LessEqual,
+                TokenType::Greater,
+                TokenType::GreaterEqual,
+                TokenType::Bang,
+                TokenType::Minus,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn keywords_must_match_the_whole_identifier() {
+        let types: Vec<_> =
+            std::iter::from_coroutine(scan("function classy orchid fortune thisway an for"))
+                .map(|token| token.typee)
+                .collect();
+        assert_eq!(
+            types,
+            [
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::Identifier,
+                TokenType::For,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(parse_number("12"), Ok(12.0));
+        assert_eq!(parse_number("1.5e-3"), Ok(0.0015));
+        assert_eq!(parse_number("0x1F"), Ok(31.0));
+        assert_eq!(parse_number("1_000.000_1"), Ok(1000.0001));
+        // decimal points and exponents are only for decimal numbers
+        assert!(parse_number("0x1.5").is_err());
+        assert!(parse_number("1e2e3").is_err());
+        assert!(parse_number("1._5").is_err());
+    }
+}
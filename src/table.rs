@@ -0,0 +1,321 @@
+use std::ptr::null_mut;
+
+use crate::{
+    memory::{allocate, free_array, grow_capacity},
+    object::ObjString,
+    value::{Value, is_nil},
+    vm::Vm,
+};
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+/// An empty bucket has a null key and a nil value, a tombstone has a null key and `true`.
+#[derive(Copy, Clone)]
+pub struct Entry {
+    pub(crate) key: *mut ObjString,
+    pub(crate) value: Value,
+}
+
+// Open addressing hash table with linear probing. Keys are interned strings, so they are compared
+// by pointer.
+pub struct Table {
+    /// number of live entries plus tombstones
+    pub(crate) count: usize,
+    pub(crate) capacity: usize,
+    pub(crate) entries: *mut Entry,
+}
+
+impl Table {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            capacity: 0,
+            entries: null_mut(),
+        }
+    }
+
+    pub fn free_table(&mut self, vm: &mut Vm) {
+        // Safety:
+        // - always allocated from calls to grow_array
+        unsafe { free_array(vm, self.entries, self.capacity) };
+        *self = Self::new();
+    }
+
+    pub fn get(&self, key: *mut ObjString) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = unsafe { &*find_entry(self.entries, self.capacity, key) };
+        if entry.key.is_null() {
+            return None;
+        }
+
+        Some(entry.value)
+    }
+
+    /// Returns true if the key wasn't already in the table.
+    pub fn set(&mut self, vm: &mut Vm, key: *mut ObjString, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity as f64 * TABLE_MAX_LOAD {
+            let capacity = grow_capacity(self.capacity);
+            self.adjust_capacity(vm, capacity);
+        }
+
+        let entry = unsafe { &mut *find_entry(self.entries, self.capacity, key) };
+        let is_new_key = entry.key.is_null();
+        // reusing a tombstone doesn't change the count, since it was already counted
+        if is_new_key && is_nil(entry.value) {
+            self.count += 1;
+        }
+
+        entry.key = key;
+        entry.value = value;
+        is_new_key
+    }
+
+    /// Returns true if the key was in the table.
+    pub fn delete(&mut self, key: *mut ObjString) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let entry = unsafe { &mut *find_entry(self.entries, self.capacity, key) };
+        if entry.key.is_null() {
+            return false;
+        }
+
+        // leave a tombstone so probe sequences that pass through this bucket keep working
+        entry.key = null_mut();
+        entry.value = Value::boolean(true);
+        true
+    }
+
+    pub fn add_all(&self, vm: &mut Vm, to: &mut Table) {
+        for i in 0..self.capacity {
+            let entry = unsafe { *self.entries.add(i) };
+            if !entry.key.is_null() {
+                to.set(vm, entry.key, entry.value);
+            }
+        }
+    }
+
+    /// Look up an interned string by its contents rather than by pointer.
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<*mut ObjString> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut index = hash as usize % self.capacity;
+        loop {
+            let entry = unsafe { &*self.entries.add(index) };
+            if entry.key.is_null() {
+                // stop if we find an empty non-tombstone entry
+                if is_nil(entry.value) {
+                    return None;
+                }
+            } else {
+                let key = unsafe { &*entry.key };
+                if key.hash == hash && key.as_str() == chars {
+                    return Some(entry.key);
+                }
+            }
+
+            index = (index + 1) % self.capacity;
+        }
+    }
+
+    /// Remove every key that wasn't marked, so the intern table doesn't keep strings alive.
+    pub fn remove_white(&mut self) {
+        for i in 0..self.capacity {
+            let entry = unsafe { *self.entries.add(i) };
+            if !entry.key.is_null() && !unsafe { (*entry.key).obj.is_marked } {
+                self.delete(entry.key);
+            }
+        }
+    }
+
+    fn adjust_capacity(&mut self, vm: &mut Vm, capacity: usize) {
+        let entries = unsafe { allocate::<Entry>(vm, capacity) };
+        for i in 0..capacity {
+            unsafe {
+                *entries.add(i) = Entry {
+                    key: null_mut(),
+                    value: Value::NIL,
+                }
+            };
+        }
+
+        // reinsert everything, dropping tombstones along the way
+        self.count = 0;
+        for i in 0..self.capacity {
+            let entry = unsafe { *self.entries.add(i) };
+            if entry.key.is_null() {
+                continue;
+            }
+
+            let dest = unsafe { &mut *find_entry(entries, capacity, entry.key) };
+            *dest = entry;
+            self.count += 1;
+        }
+
+        unsafe { free_array(vm, self.entries, self.capacity) };
+        self.entries = entries;
+        self.capacity = capacity;
+    }
+}
+
+/// Find the bucket for `key`, or the bucket it should be inserted into. Prefers the first tombstone
+/// passed over so it gets reused.
+///
+/// Capacity must be non-zero and the table must have at least one empty bucket.
+fn find_entry(entries: *mut Entry, capacity: usize, key: *mut ObjString) -> *mut Entry {
+    let mut index = unsafe { (*key).hash } as usize % capacity;
+    let mut tombstone: *mut Entry = null_mut();
+    loop {
+        let entry = unsafe { entries.add(index) };
+        let Entry {
+            key: entry_key,
+            value,
+        } = unsafe { *entry };
+        if entry_key.is_null() {
+            if is_nil(value) {
+                // empty entry
+                return if tombstone.is_null() {
+                    entry
+                } else {
+                    tombstone
+                };
+            } else if tombstone.is_null() {
+                tombstone = entry;
+            }
+        } else if entry_key == key {
+            return entry;
+        }
+
+        index = (index + 1) % capacity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null_mut;
+
+    use super::Table;
+    use crate::{
+        object::{Obj, ObjString, ObjType, hash_string},
+        value::Value,
+        vm::Vm,
+    };
+
+    // keys are boxed rather than allocated through the vm, so they aren't freed by a collection
+    fn key(chars: &'static str) -> Box<ObjString> {
+        Box::new(ObjString {
+            obj: Obj {
+                typee: ObjType::String,
+                is_marked: false,
+                next: null_mut(),
+            },
+            length: chars.len(),
+            chars: chars.as_ptr() as *mut u8,
+            hash: hash_string(chars),
+        })
+    }
+
+    fn as_f64(value: Option<Value>) -> Option<f64> {
+        value.map(|value| value.try_into().unwrap())
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut vm = Vm::new();
+        let mut table = Table::new();
+        let mut a = key("a");
+        let mut b = key("b");
+
+        assert!(table.set(&mut vm, &mut *a, Value::number(1.0)));
+        assert!(table.set(&mut vm, &mut *b, Value::number(2.0)));
+        assert!(!table.set(&mut vm, &mut *a, Value::number(3.0)));
+
+        assert_eq!(as_f64(table.get(&mut *a)), Some(3.0));
+        assert_eq!(as_f64(table.get(&mut *b)), Some(2.0));
+        assert_eq!(as_f64(table.get(&mut *key("a"))), None);
+        table.free_table(&mut vm);
+    }
+
+    #[test]
+    fn delete_leaves_tombstone() {
+        let mut vm = Vm::new();
+        let mut table = Table::new();
+        let mut keys: Vec<_> = ["a", "b", "c", "d", "e"].into_iter().map(key).collect();
+        for (i, key) in keys.iter_mut().enumerate() {
+            table.set(&mut vm, &mut **key, Value::number(i as f64));
+        }
+
+        assert!(table.delete(&mut *keys[1]));
+        assert!(!table.delete(&mut *keys[1]));
+        assert_eq!(as_f64(table.get(&mut *keys[1])), None);
+        // tombstones are still counted towards the load factor
+        assert_eq!(table.count, 5);
+        for i in [0, 2, 3, 4] {
+            assert_eq!(as_f64(table.get(&mut *keys[i])), Some(i as f64));
+        }
+
+        // reinserting reuses the tombstone
+        assert!(table.set(&mut vm, &mut *keys[1], Value::number(10.0)));
+        assert_eq!(table.count, 5);
+        assert_eq!(as_f64(table.get(&mut *keys[1])), Some(10.0));
+        table.free_table(&mut vm);
+    }
+
+    #[test]
+    fn grows_past_load_factor() {
+        let names: Vec<&'static str> = (0..100)
+            .map(|i| &*Box::leak(format!("key{i}").into_boxed_str()))
+            .collect();
+        let mut keys: Vec<_> = names.iter().map(|name| key(name)).collect();
+
+        let mut vm = Vm::new();
+        let mut table = Table::new();
+        for (i, key) in keys.iter_mut().enumerate() {
+            table.set(&mut vm, &mut **key, Value::number(i as f64));
+        }
+
+        assert_eq!(table.count, 100);
+        assert!(table.count as f64 <= table.capacity as f64 * super::TABLE_MAX_LOAD);
+        for (i, key) in keys.iter_mut().enumerate() {
+            assert_eq!(as_f64(table.get(&mut **key)), Some(i as f64));
+        }
+        table.free_table(&mut vm);
+    }
+
+    #[test]
+    fn find_string_by_contents() {
+        let mut vm = Vm::new();
+        let mut table = Table::new();
+        let mut hello = key("hello");
+        table.set(&mut vm, &mut *hello, Value::NIL);
+
+        let found = table.find_string("hello", hash_string("hello"));
+        assert_eq!(found, Some(&mut *hello as *mut ObjString));
+        assert_eq!(table.find_string("world", hash_string("world")), None);
+        table.free_table(&mut vm);
+    }
+
+    #[test]
+    fn add_all_copies_entries() {
+        let mut vm = Vm::new();
+        let mut from = Table::new();
+        let mut to = Table::new();
+        let mut a = key("a");
+        let mut b = key("b");
+        from.set(&mut vm, &mut *a, Value::number(1.0));
+        from.set(&mut vm, &mut *b, Value::number(2.0));
+        from.delete(&mut *b);
+
+        from.add_all(&mut vm, &mut to);
+        assert_eq!(as_f64(to.get(&mut *a)), Some(1.0));
+        assert_eq!(as_f64(to.get(&mut *b)), None);
+        from.free_table(&mut vm);
+        to.free_table(&mut vm);
+    }
+}
@@ -1,24 +1,41 @@
 use std::{fmt::Display, ptr::null_mut};
 
-use crate::memory::{free_array, grow_array, grow_capacity};
+use crate::{
+    memory::{free_array, grow_array, grow_capacity},
+    object::{Obj, ObjDisplay},
+    vm::Vm,
+};
 
+/// A lox value. By default this is a tagged enum, with the `nan_boxing` feature every value is
+/// packed into the bits of a single `f64` instead. Code outside this module only goes through the
+/// constructors and the `is_*`/`as_*` helpers below, so it works with either representation.
+#[cfg(not(feature = "nan_boxing"))]
 #[derive(Copy, Clone)]
 pub enum Value {
     Double(f64),
     Bool(bool),
     Nil,
+    Obj(*mut Obj),
 }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Double(value) => value.fmt(f),
-            Value::Bool(value) => value.fmt(f),
-            Value::Nil => f.write_str("Nil"),
-        }
+#[cfg(not(feature = "nan_boxing"))]
+impl Value {
+    pub const NIL: Value = Value::Nil;
+
+    pub const fn number(value: f64) -> Value {
+        Value::Double(value)
+    }
+
+    pub const fn boolean(value: bool) -> Value {
+        Value::Bool(value)
+    }
+
+    pub(crate) fn obj(object: *mut Obj) -> Value {
+        Value::Obj(object)
     }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
 impl TryFrom<Value> for f64 {
     type Error = &'static str;
 
@@ -30,6 +47,7 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
 impl TryFrom<Value> for bool {
     type Error = &'static str;
 
@@ -41,6 +59,174 @@ impl TryFrom<Value> for bool {
     }
 }
 
+#[cfg(not(feature = "nan_boxing"))]
+pub fn values_equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Double(a), Value::Double(b)) => a == b,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        // strings are interned, so comparing pointers also compares contents
+        (Value::Obj(a), Value::Obj(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+pub fn is_number(value: Value) -> bool {
+    matches!(value, Value::Double(_))
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+pub fn is_bool(value: Value) -> bool {
+    matches!(value, Value::Bool(_))
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+pub fn is_nil(value: Value) -> bool {
+    matches!(value, Value::Nil)
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+pub fn is_obj(value: Value) -> bool {
+    matches!(value, Value::Obj(_))
+}
+
+#[cfg(not(feature = "nan_boxing"))]
+pub fn as_obj(value: Value) -> *mut Obj {
+    match value {
+        Value::Obj(object) => object,
+        _ => panic!("not an object"),
+    }
+}
+
+// Any double whose quiet NaN bits are all set is treated as a tagged value rather than a number.
+// NaNs produced by arithmetic only set the highest mantissa bit, so they never collide with the
+// tags. Objects set the sign bit and store the pointer in the low 48 bits,
+// singletons use the low two bits as a tag.
+#[cfg(feature = "nan_boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan_boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan_boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan_boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan_boxing")]
+const TAG_TRUE: u64 = 3;
+
+#[cfg(feature = "nan_boxing")]
+#[derive(Copy, Clone)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan_boxing")]
+impl Value {
+    pub const NIL: Value = Value(QNAN | TAG_NIL);
+    const FALSE: Value = Value(QNAN | TAG_FALSE);
+    const TRUE: Value = Value(QNAN | TAG_TRUE);
+
+    pub const fn number(value: f64) -> Value {
+        // a NaN can carry any payload, including the bits of nil, a boolean or an object pointer,
+        // so every NaN is stored as the one quiet NaN the tags stay clear of
+        if value.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(value.to_bits())
+        }
+    }
+
+    pub const fn boolean(value: bool) -> Value {
+        if value { Value::TRUE } else { Value::FALSE }
+    }
+
+    pub(crate) fn obj(object: *mut Obj) -> Value {
+        Value(SIGN_BIT | QNAN | object as u64)
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl TryFrom<Value> for f64 {
+    type Error = &'static str;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if is_number(value) {
+            Ok(f64::from_bits(value.0))
+        } else {
+            Err("not a double")
+        }
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+impl TryFrom<Value> for bool {
+    type Error = &'static str;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if is_bool(value) {
+            Ok(value.0 == Value::TRUE.0)
+        } else {
+            Err("not a bool")
+        }
+    }
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn values_equal(a: Value, b: Value) -> bool {
+    // compare numbers as doubles so NaN is still not equal to itself
+    if is_number(a) && is_number(b) {
+        return f64::from_bits(a.0) == f64::from_bits(b.0);
+    }
+    // strings are interned, so comparing pointers also compares contents
+    a.0 == b.0
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn is_number(value: Value) -> bool {
+    value.0 & QNAN != QNAN
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn is_bool(value: Value) -> bool {
+    // true and false only differ in the lowest bit
+    value.0 | 1 == Value::TRUE.0
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn is_nil(value: Value) -> bool {
+    value.0 == Value::NIL.0
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn is_obj(value: Value) -> bool {
+    value.0 & (QNAN | SIGN_BIT) == QNAN | SIGN_BIT
+}
+
+#[cfg(feature = "nan_boxing")]
+pub fn as_obj(value: Value) -> *mut Obj {
+    assert!(is_obj(value), "not an object");
+    (value.0 & !(SIGN_BIT | QNAN)) as *mut Obj
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = *self;
+        if let Ok(number) = f64::try_from(value) {
+            number.fmt(f)
+        } else if let Ok(boolean) = bool::try_from(value) {
+            boolean.fmt(f)
+        } else if is_nil(value) {
+            f.write_str("Nil")
+        } else {
+            ObjDisplay(as_obj(value)).fmt(f)
+        }
+    }
+}
+
 // This would make more sense as new typed Vec<Value>, but for learning purposes we're going to play
 // with allocation
 pub struct ValueArray {
@@ -58,11 +244,12 @@ impl ValueArray {
         }
     }
 
-    pub fn write_value_array(&mut self, value: Value) {
+    pub fn write_value_array(&mut self, vm: &mut Vm, value: Value) {
         if self.capacity < self.count + 1 {
             let old_capacity = self.capacity;
             self.capacity = grow_capacity(old_capacity);
-            self.values = unsafe { grow_array::<Value>(self.values, old_capacity, self.capacity) };
+            self.values =
+                unsafe { grow_array::<Value>(vm, self.values, old_capacity, self.capacity) };
         }
 
         // Safety:
@@ -72,27 +259,87 @@ impl ValueArray {
         self.count += 1;
     }
 
-    pub fn free_value_array(&mut self) {
+    pub fn free_value_array(&mut self, vm: &mut Vm) {
         // Safety:
         // - always allocated from calls to grow_array
-        unsafe { free_array(self.values, self.capacity) };
+        unsafe { free_array(vm, self.values, self.capacity) };
         *self = Self::new();
     }
 
-    pub fn print_value(&self, index: u8) {
-        print!("{}", unsafe { *self.values.add(index as usize) })
+    pub fn print_value(&self, index: usize) {
+        print!("{}", unsafe { *self.values.add(index) })
     }
 }
 
-pub fn values_equal(a: Value, b: Value) -> bool {
-    match (a, b) {
-        (Value::Double(a), Value::Double(b)) => a == b,
-        (Value::Bool(a), Value::Bool(b)) => a == b,
-        (Value::Nil, Value::Nil) => true,
-        _ => unreachable!(),
+#[cfg(test)]
+mod tests {
+    use super::{Value, as_obj, is_bool, is_nil, is_number, is_obj, values_equal};
+    use crate::object::Obj;
+
+    #[test]
+    fn numbers_round_trip() {
+        for number in [
+            0.0,
+            -0.0,
+            1.5,
+            -3.25,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+        ] {
+            let value = Value::number(number);
+            assert!(is_number(value));
+            assert!(!is_bool(value) && !is_nil(value) && !is_obj(value));
+            assert_eq!(f64::try_from(value), Ok(number));
+        }
+
+        let nan = Value::number(f64::NAN);
+        assert!(is_number(nan));
+        assert!(!values_equal(nan, nan));
     }
-}
 
-pub fn is_nil(value: Value) -> bool {
-    matches!(value, Value::Nil)
+    #[test]
+    fn nans_with_payloads_stay_numbers() {
+        for bits in [
+            0x7ffc_0000_0000_0001,
+            0x7ffc_0000_0000_0002,
+            0xfffc_0000_0000_1000,
+            0xffff_ffff_ffff_ffff,
+        ] {
+            let value = Value::number(f64::from_bits(bits));
+            assert!(is_number(value));
+            assert!(!is_nil(value) && !is_bool(value) && !is_obj(value));
+            assert!(f64::try_from(value).unwrap().is_nan());
+        }
+    }
+
+    #[test]
+    fn singletons_are_distinct() {
+        let values = [Value::NIL, Value::boolean(true), Value::boolean(false)];
+        assert!(is_nil(values[0]) && !is_bool(values[0]));
+        assert_eq!(bool::try_from(values[1]), Ok(true));
+        assert_eq!(bool::try_from(values[2]), Ok(false));
+        for (i, a) in values.iter().enumerate() {
+            assert!(!is_number(*a) && !is_obj(*a));
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(values_equal(*a, *b), i == j);
+            }
+        }
+        assert!(!values_equal(Value::boolean(false), Value::number(0.0)));
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        let mut a = Box::new(0u64);
+        let mut b = Box::new(0u64);
+        let a = &mut *a as *mut u64 as *mut Obj;
+        let b = &mut *b as *mut u64 as *mut Obj;
+
+        let value = Value::obj(a);
+        assert!(is_obj(value));
+        assert!(!is_number(value) && !is_bool(value) && !is_nil(value));
+        assert_eq!(as_obj(value), a);
+        assert!(values_equal(value, Value::obj(a)));
+        assert!(!values_equal(value, Value::obj(b)));
+    }
 }
@@ -1,188 +1,1051 @@
-use std::{fmt::Display, ptr::null_mut};
+use std::{fmt::Display, ptr::null_mut, rc::Rc};
 
 use crate::{
-    chunk::{Chunk, OpCode},
-    compiler::compile,
-    value::{Value, is_nil, values_equal},
+    chunk::OpCode,
+    compiler::{Compiler, compile},
+    diagnostic::{Diagnostic, Span, line_bounds, quote},
+    handle::{self, Roots},
+    memory::{allocate, free_objects},
+    native::BUILTINS,
+    object::{
+        Native, NativeFn, Obj, ObjClass, ObjClosure, ObjFunction, ObjString, ObjType, ObjUpvalue,
+        as_bound_method, as_class, as_closure, as_function, as_instance, as_native, as_string,
+        copy_string, hash_string, is_class, is_instance, is_string, new_bound_method, new_class,
+        new_closure, new_instance, new_native, new_upvalue, obj_type, take_string,
+    },
+    table::Table,
+    value::{Value, is_nil, is_obj, values_equal},
 };
 
-const STACK_MAX: usize = 256;
+/// Slots the stack starts with, it grows on demand up to `Limits::max_stack`.
+const STACK_INITIAL: usize = u8::MAX as usize + 1;
 
-pub struct Vm {
-    pub(crate) chunk: *mut Chunk,
-    /// pointer to current instruction
+/// How deep a script is allowed to go before it fails with a stack overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Most values on the stack at once, counting the locals and temporaries of every call.
+    pub max_stack: usize,
+    /// Most nested calls, including the top level script.
+    pub max_frames: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        let max_frames = 64;
+        Limits {
+            max_stack: max_frames * (u8::MAX as usize + 1),
+            max_frames,
+        }
+    }
+}
+
+/// A single ongoing function call
+#[derive(Clone, Copy)]
+pub(crate) struct CallFrame {
+    pub(crate) closure: *mut ObjClosure,
+    /// pointer to current instruction in the function's chunk
     instruction_pointer: *mut u8,
-    stack: [Value; STACK_MAX],
+    /// index of the first stack slot the function can use
+    slots: usize,
+}
+
+/// A compiled top level script, see [`Vm::compile`]. Its function stays alive until every clone
+/// of the `Script` is dropped.
+#[derive(Debug, Clone)]
+pub struct Script {
+    function: handle::Value,
+}
+
+/// A lox virtual machine with its own globals and heap.
+pub struct Vm {
+    /// grows on demand, only the first `frame_count` frames are in use
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) frame_count: usize,
+    /// every slot is initialized, only the ones below `stack_top` are in use. Growing it moves the
+    /// values, so open upvalues are rebased when it does.
+    pub(crate) stack: Vec<Value>,
     /// index of next empty stack slot
-    stack_top: usize,
+    pub(crate) stack_top: usize,
+    limits: Limits,
+    /// `frame_count` and `stack_top` when the innermost `interpret` or `execute` started. A native
+    /// can run a script while another one is running, the inner script returns and unwinds only
+    /// down to here.
+    entry_frame: usize,
+    entry_stack: usize,
+    /// open upvalues sorted by stack slot, with the top most slot first
+    pub(crate) open_upvalues: *mut ObjUpvalue,
+    /// head of the list of every allocated object
+    pub(crate) objects: *mut Obj,
+    pub(crate) globals: Table,
+    /// interned strings, used as a set
+    pub(crate) strings: Table,
+    /// name of class initializers, kept around to avoid looking it up on every call
+    pub(crate) init_string: *mut ObjString,
+    /// innermost function being compiled, null outside of compilation
+    pub(crate) compiler: *mut Compiler,
+    /// values the host holds on to
+    pub(crate) roots: Rc<Roots>,
+
+    /// bytes currently allocated through `reallocate`
+    pub(crate) bytes_allocated: usize,
+    /// collect garbage once `bytes_allocated` passes this
+    pub(crate) next_gc: usize,
+    /// marked objects whose references haven't been traced yet
+    pub(crate) gray_count: usize,
+    pub(crate) gray_capacity: usize,
+    pub(crate) gray_stack: *mut *mut Obj,
 }
 
-// TODO: not really send and sync, but we do this to make it a global static.
-// if we actually access this from multiple threads it will be UB.
-unsafe impl Sync for Vm {}
-unsafe impl Send for Vm {}
+impl Vm {
+    /// A fresh vm with the builtin natives defined and the default [`Limits`].
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
 
-// TODO: this is very unsafe and probably will have UB. But we do it to mirror the book and will refactor
-// later
-pub static mut VM: Vm = Vm {
-    chunk: null_mut(),
-    instruction_pointer: null_mut(),
-    stack: [Value::Double(0.0); STACK_MAX],
-    stack_top: 0,
-};
+    /// A fresh vm whose scripts fail with a stack overflow once they go past `limits`.
+    ///
+    /// Panics if `limits.max_frames` is zero.
+    pub fn with_limits(limits: Limits) -> Self {
+        assert!(limits.max_frames > 0, "the top level script needs a frame");
+        let mut vm = Vm {
+            frames: Vec::new(),
+            frame_count: 0,
+            stack: vec![Value::NIL; STACK_INITIAL],
+            stack_top: 0,
+            limits,
+            entry_frame: 0,
+            entry_stack: 0,
+            open_upvalues: null_mut(),
+            objects: null_mut(),
+            globals: Table::new(),
+            strings: Table::new(),
+            // copy_string can trigger a collection, which reads init_string
+            init_string: null_mut(),
+            compiler: null_mut(),
+            roots: Roots::new(),
+            bytes_allocated: 0,
+            next_gc: 1024 * 1024,
+            gray_count: 0,
+            gray_capacity: 0,
+            gray_stack: null_mut(),
+        };
+        vm.init_string = copy_string(&mut vm, "init");
 
-fn reset_stack() {
-    unsafe {
-        VM.stack_top = 0;
+        for (name, function) in BUILTINS {
+            vm.define(name, Native::Builtin(function));
+        }
+        vm
     }
-}
 
-fn runtime_error(err: impl Display) {
-    // get the line number
-    let instruction_index = unsafe { VM.instruction_pointer.offset_from((*VM.chunk).code) };
-    // we want the previous instruction, since the pointer was already advanced
-    let instruction_index = instruction_index - 1;
-    let line = unsafe { (*VM.chunk).lines.add(instruction_index as usize) };
-    println!("{err} {} in script", unsafe { *line });
-    reset_stack();
-}
+    /// Discard everything the innermost running script pushed.
+    fn reset_stack(&mut self) {
+        // closures that escaped the failed script keep their variables, even once the slots are
+        // reused or the stack moves
+        let bottom = unsafe { self.stack.as_mut_ptr().add(self.entry_stack) };
+        self.close_upvalues(bottom);
+        self.stack_top = self.entry_stack;
+        self.frame_count = self.entry_frame;
+    }
 
-fn push(value: Value) {
-    unsafe {
-        VM.stack[VM.stack_top] = value;
+    // NOTE: lifetime here is incorrect
+    fn frame(&mut self) -> &'static mut CallFrame {
+        let frame = &raw mut self.frames[self.frame_count - 1];
+        unsafe { &mut *frame }
     }
-    unsafe {
-        VM.stack_top += 1;
+
+    /// Build the error for a failed instruction and unwind the stack.
+    fn runtime_error(&mut self, err: impl Display) -> LoxError {
+        let trace = self.frames[self.entry_frame..self.frame_count]
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = unsafe { &*(*frame.closure).function };
+                let instruction_index =
+                    unsafe { frame.instruction_pointer.offset_from(function.chunk.code) };
+                // we want the previous instruction, since the pointer was already advanced
+                let instruction_index = instruction_index as usize - 1;
+                let line = function.chunk.get_line(instruction_index);
+                let span = function.chunk.get_span(instruction_index);
+
+                let source = unsafe { (*function.source).as_str() };
+                let (line_start, line_end) = line_bounds(source, span.start);
+                let column = source[line_start..span.start].chars().count() + 1;
+
+                TraceFrame {
+                    function: (!function.name.is_null())
+                        .then(|| unsafe { (*function.name).as_str() }.to_string()),
+                    line,
+                    column,
+                    span,
+                    source_line: source[line_start..line_end].to_string(),
+                }
+            })
+            .collect();
+        self.reset_stack();
+        LoxError::Runtime {
+            message: err.to_string(),
+            trace,
+        }
     }
-}
 
-fn pop() -> Value {
-    unsafe {
-        VM.stack_top -= 1;
+    pub(crate) fn push(&mut self, value: Value) {
+        if self.stack_top == self.stack.len() {
+            self.grow_stack();
+        }
+        self.stack[self.stack_top] = value;
+        self.stack_top += 1;
     }
-    unsafe { VM.stack[VM.stack_top] }
-}
 
-fn peek(distance: isize) -> Value {
-    let peek = (1 + distance) as usize;
-    unsafe { VM.stack[VM.stack_top - peek] }
-}
+    fn grow_stack(&mut self) {
+        let old_base = self.stack.as_mut_ptr();
+        self.stack.resize(self.stack.len() * 2, Value::NIL);
+        let new_base = self.stack.as_mut_ptr();
 
-fn is_falsey(value: Value) -> bool {
-    is_nil(value) || !TryFrom::try_from(value).unwrap_or(true)
-}
+        // open upvalues point into the old allocation, move them to the same slot in the new one
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() {
+            unsafe {
+                let slot = (*upvalue).location.offset_from(old_base);
+                (*upvalue).location = new_base.offset(slot);
+                upvalue = (*upvalue).next;
+            }
+        }
+    }
 
-// These methods might be a little too "C" and should be converted to a more rust styld.
-pub fn init_vm() {
-    reset_stack();
-}
+    pub(crate) fn pop(&mut self) -> Value {
+        self.stack_top -= 1;
+        self.stack[self.stack_top]
+    }
 
-pub fn free_vm() {}
+    fn peek(&self, distance: isize) -> Value {
+        let peek = (1 + distance) as usize;
+        self.stack[self.stack_top - peek]
+    }
 
-// should consider making this lifetimed
-pub fn interpret(source: &str) -> Result<(), InterpretError> {
-    let mut chunk = Chunk::new();
+    fn call(&mut self, closure: *mut ObjClosure, arg_count: u8) -> Result<(), LoxError> {
+        let function = unsafe { (*closure).function };
+        let arity = unsafe { (*function).arity };
+        if arg_count as usize != arity {
+            return Err(
+                self.runtime_error(format!("Expected {arity} arguments but got {arg_count}."))
+            );
+        }
+
+        if self.frame_count == self.limits.max_frames || self.stack_top > self.limits.max_stack {
+            return Err(self.runtime_error("Stack overflow."));
+        }
 
-    if !compile(source, &mut chunk) {
-        chunk.free_chunk();
+        let frame = CallFrame {
+            closure,
+            instruction_pointer: unsafe { (*function).chunk.code },
+            // the callee and its arguments are already on the stack and become its first slots
+            slots: self.stack_top - arg_count as usize - 1,
+        };
+        if self.frame_count == self.frames.len() {
+            self.frames.push(frame);
+        } else {
+            self.frames[self.frame_count] = frame;
+        }
+        self.frame_count += 1;
+        Ok(())
     }
 
-    unsafe {
-        VM.chunk = &mut chunk as *mut Chunk;
-        VM.instruction_pointer = (*VM.chunk).code;
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), LoxError> {
+        if is_obj(callee) {
+            match obj_type(callee) {
+                ObjType::BoundMethod => {
+                    let bound = as_bound_method(callee);
+                    // the receiver goes in slot zero, where the method expects `this`
+                    self.stack[self.stack_top - arg_count as usize - 1] =
+                        unsafe { (*bound).receiver };
+                    return self.call(unsafe { (*bound).method }, arg_count);
+                }
+                ObjType::Class => {
+                    let class = as_class(callee);
+                    let instance = new_instance(self, class);
+                    // the instance replaces the class in the callee slot
+                    self.stack[self.stack_top - arg_count as usize - 1] =
+                        Value::obj(instance as *mut Obj);
+
+                    if let Some(initializer) = unsafe { (*class).methods.get(self.init_string) } {
+                        return self.call(as_closure(initializer), arg_count);
+                    } else if arg_count != 0 {
+                        return Err(self
+                            .runtime_error(format!("Expected 0 arguments but got {arg_count}.")));
+                    }
+                    return Ok(());
+                }
+                ObjType::Closure => return self.call(as_closure(callee), arg_count),
+                ObjType::Native => {
+                    let native = as_native(callee);
+                    let args_start = self.stack_top - arg_count as usize;
+                    // copied, since the native can push values and grow the stack out from under
+                    // a borrowed slice
+                    let args = self.stack[args_start..self.stack_top].to_vec();
+                    let result = match native {
+                        Native::Builtin(function) => function(self, &args),
+                        Native::Host(function) => self.call_host(function, &args),
+                    };
+                    return match result {
+                        Ok(result) => {
+                            // discard the arguments and the native itself
+                            self.stack_top = args_start - 1;
+                            self.push(result);
+                            Ok(())
+                        }
+                        Err(message) => Err(self.runtime_error(message)),
+                    };
+                }
+                // non-callable object type
+                ObjType::Function | ObjType::Instance | ObjType::String | ObjType::Upvalue => {}
+            }
+        }
+
+        Err(self.runtime_error("Can only call functions and classes."))
     }
 
-    let result = run();
+    fn invoke_from_class(
+        &mut self,
+        class: *mut ObjClass,
+        name: *mut ObjString,
+        arg_count: u8,
+    ) -> Result<(), LoxError> {
+        let Some(method) = (unsafe { (*class).methods.get(name) }) else {
+            return Err(
+                self.runtime_error(format!("Undefined property '{}'.", unsafe {
+                    (*name).as_str()
+                })),
+            );
+        };
+        self.call(as_closure(method), arg_count)
+    }
 
-    chunk.free_chunk();
-    result
-}
+    fn invoke(&mut self, name: *mut ObjString, arg_count: u8) -> Result<(), LoxError> {
+        let receiver = self.peek(arg_count as isize);
+        if !is_instance(receiver) {
+            return Err(self.runtime_error("Only instances have methods."));
+        }
 
-fn run() -> Result<(), InterpretError> {
-    // #define READ_BTYE() (*vm.instruction_pointer++);
-    fn read_byte() -> u8 {
-        let byte = unsafe { *VM.instruction_pointer };
+        let instance = as_instance(receiver);
+        // fields shadow methods, and could hold any callable
+        if let Some(value) = unsafe { (*instance).fields.get(name) } {
+            self.stack[self.stack_top - arg_count as usize - 1] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(unsafe { (*instance).class }, name, arg_count)
+    }
+
+    /// Replace the instance on top of the stack with its method `name`, bound to the instance.
+    fn bind_method(&mut self, class: *mut ObjClass, name: *mut ObjString) -> Result<(), LoxError> {
+        let Some(method) = (unsafe { (*class).methods.get(name) }) else {
+            return Err(
+                self.runtime_error(format!("Undefined property '{}'.", unsafe {
+                    (*name).as_str()
+                })),
+            );
+        };
+
+        let bound = new_bound_method(self, self.peek(0), as_closure(method));
+        self.pop();
+        self.push(Value::obj(bound as *mut Obj));
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, local: *mut Value) -> *mut ObjUpvalue {
+        // reuse the existing upvalue if another closure already captured this slot
+        let mut prev_upvalue: *mut ObjUpvalue = null_mut();
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() && unsafe { (*upvalue).location } > local {
+            prev_upvalue = upvalue;
+            upvalue = unsafe { (*upvalue).next };
+        }
+
+        if !upvalue.is_null() && unsafe { (*upvalue).location } == local {
+            return upvalue;
+        }
+
+        let created_upvalue = new_upvalue(self, local);
+        unsafe { (*created_upvalue).next = upvalue };
+        if prev_upvalue.is_null() {
+            self.open_upvalues = created_upvalue;
+        } else {
+            unsafe { (*prev_upvalue).next = created_upvalue };
+        }
+        created_upvalue
+    }
+
+    /// Close every open upvalue pointing at `last` or any slot above it.
+    fn close_upvalues(&mut self, last: *mut Value) {
+        while !self.open_upvalues.is_null() && unsafe { (*self.open_upvalues).location } >= last {
+            let upvalue = self.open_upvalues;
+            unsafe {
+                (*upvalue).closed = *(*upvalue).location;
+                (*upvalue).location = &raw mut (*upvalue).closed;
+                self.open_upvalues = (*upvalue).next;
+            }
+        }
+    }
+
+    /// Call a native registered by the host, handing it the arguments through handles.
+    fn call_host(&mut self, function: NativeFn, args: &[Value]) -> Result<Value, String> {
+        let args: Vec<_> = args
+            .iter()
+            .map(|&arg| handle::Value::new(&self.roots, arg))
+            .collect();
+        let result = function(self, &args)?;
+        result
+            .get(&self.roots)
+            .ok_or_else(|| "Native returned a value from a different vm.".to_string())
+    }
+
+    /// Expose a rust function to lox scripts as a global.
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        self.define(name, Native::Host(function));
+    }
+
+    fn define(&mut self, name: &str, native: Native) {
+        let name = copy_string(self, name);
+        self.push(Value::obj(name as *mut Obj));
+        let native = new_native(self, native);
+        self.push(Value::obj(native as *mut Obj));
+        let globals = &raw mut self.globals;
+        unsafe { (*globals).set(self, as_string(self.peek(1)), self.peek(0)) };
+        self.pop();
+        self.pop();
+    }
+
+    fn concatenate(&mut self) {
+        // leave the operands on the stack until the result is allocated, so the gc can see them
+        let b = unsafe { &*as_string(self.peek(0)) };
+        let a = unsafe { &*as_string(self.peek(1)) };
+
+        let length = a.length + b.length;
+        let chars = unsafe { allocate::<u8>(self, length) };
+        // Safety: chars has room for both strings and doesn't overlap either of them.
         unsafe {
-            VM.instruction_pointer = VM.instruction_pointer.add(1);
+            std::ptr::copy_nonoverlapping(a.chars, chars, a.length);
+            std::ptr::copy_nonoverlapping(b.chars, chars.add(a.length), b.length);
         }
+
+        // Safety: chars was allocated above and the concatenation of two utf8 strings is utf8.
+        let result = unsafe { take_string(self, chars, length) };
+        self.pop();
+        self.pop();
+        self.push(Value::obj(result as *mut Obj));
+    }
+
+    /// Compile and run `source`, returning the value of its last expression statement, or nil.
+    pub fn interpret(&mut self, source: &str) -> Result<handle::Value, LoxError> {
+        let function =
+            compile(self, source).map_err(|diagnostics| LoxError::Compile { diagnostics })?;
+        self.run_function(function)
+    }
+
+    /// Compile `source` once so it can be run any number of times with [`Vm::execute`].
+    pub fn compile(&mut self, source: &str) -> Result<Script, LoxError> {
+        let function =
+            compile(self, source).map_err(|diagnostics| LoxError::Compile { diagnostics })?;
+        Ok(Script {
+            function: handle::Value::new(&self.roots, Value::obj(function as *mut Obj)),
+        })
+    }
+
+    /// Run a script compiled by this vm.
+    ///
+    /// Panics if `script` was compiled by a different vm.
+    pub fn execute(&mut self, script: &Script) -> Result<handle::Value, LoxError> {
+        let function = script
+            .function
+            .get(&self.roots)
+            .expect("script was compiled by a different vm");
+        self.run_function(as_function(function))
+    }
+
+    fn run_function(&mut self, function: *mut ObjFunction) -> Result<handle::Value, LoxError> {
+        // when a native runs a script, the frames of the script that called it stay untouched
+        let outer_entry = (self.entry_frame, self.entry_stack);
+        self.entry_frame = self.frame_count;
+        self.entry_stack = self.stack_top;
+
+        self.push(Value::obj(function as *mut Obj));
+        let closure = new_closure(self, function);
+        self.pop();
+        self.push(Value::obj(closure as *mut Obj));
+        let result = self.call(closure, 0).and_then(|()| self.run());
+
+        (self.entry_frame, self.entry_stack) = outer_entry;
+        result.map(|value| handle::Value::new(&self.roots, value))
+    }
+
+    /// Look up a global variable.
+    pub fn get_global(&self, name: &str) -> Option<handle::Value> {
+        // a name that was never interned can't be a global
+        let name = self.strings.find_string(name, hash_string(name))?;
+        let value = self.globals.get(name)?;
+        Some(handle::Value::new(&self.roots, value))
+    }
+
+    /// Define or overwrite a global variable.
+    ///
+    /// Panics if `value` is an object from a different vm.
+    pub fn set_global(&mut self, name: &str, value: handle::Value) {
+        let value = value
+            .get(&self.roots)
+            .expect("value belongs to a different vm");
+        // keep both reachable while the name is allocated and the table grows
+        self.push(value);
+        let name = copy_string(self, name);
+        self.push(Value::obj(name as *mut Obj));
+        let globals = &raw mut self.globals;
+        unsafe { (*globals).set(self, name, self.peek(1)) };
+        self.pop();
+        self.pop();
+    }
+
+    /// Allocate a lox string.
+    pub fn new_string(&mut self, chars: &str) -> handle::Value {
+        let string = copy_string(self, chars);
+        handle::Value::new(&self.roots, Value::obj(string as *mut Obj))
+    }
+
+    // #define READ_BTYE() (*vm.instruction_pointer++);
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame();
+        let byte = unsafe { *frame.instruction_pointer };
+        frame.instruction_pointer = unsafe { frame.instruction_pointer.add(1) };
         byte
     }
 
-    fn read_constant() -> Value {
-        let chunk = unsafe { &mut *VM.chunk };
-        unsafe { *chunk.constants.values.add(read_byte() as usize) }
+    fn read_short(&mut self) -> u16 {
+        u16::from_be_bytes([self.read_byte(), self.read_byte()])
     }
 
-    macro_rules! binary_op {
-        ($variant:expr, $op:tt) => {
-            {
-                let (Ok(b), Ok(a)) = (TryInto::<f64>::try_into(peek(0)), TryInto::<f64>::try_into(peek(1))) else {
-                    runtime_error("Operands must be a numbers.");
-                    return Err(InterpretError::RuntimeError);
-                };
-                pop();
-                pop();
-                push($variant(a $op b));
-            }
+    /// Read the constant operand of `instruction`, which is 24 bits for the long forms.
+    fn read_constant(&mut self, instruction: OpCode) -> Value {
+        let index = if instruction.is_long() {
+            u32::from_be_bytes([0, self.read_byte(), self.read_byte(), self.read_byte()]) as usize
+        } else {
+            self.read_byte() as usize
         };
+        let chunk = unsafe { &(*(*self.frame().closure).function).chunk };
+        unsafe { *chunk.constants.values.add(index) }
     }
 
-    loop {
-        #[cfg(feature = "debug_trace_execution")]
-        {
-            print!("          ");
-            let mut slot = unsafe { &mut VM.stack[0] as *mut Value };
-            while slot != unsafe { VM.stack_top } {
-                print!("[ {} ]", unsafe { *slot });
-                slot = unsafe { slot.add(1) };
+    fn read_string(&mut self, instruction: OpCode) -> *mut ObjString {
+        as_string(self.read_constant(instruction))
+    }
+
+    fn run(&mut self) -> Result<Value, LoxError> {
+        macro_rules! binary_op {
+            ($variant:expr, $op:tt) => {
+                {
+                    let (Ok(b), Ok(a)) = (TryInto::<f64>::try_into(self.peek(0)), TryInto::<f64>::try_into(self.peek(1))) else {
+                        return Err(self.runtime_error("Operands must be a numbers."));
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push($variant(a $op b));
+                }
+            };
+        }
+
+        loop {
+            // an instruction pushes at most one value, so checking between instructions keeps the
+            // stack within one slot of the limit without making every push fallible
+            if self.stack_top > self.limits.max_stack {
+                return Err(self.runtime_error("Stack overflow."));
             }
-            println!();
 
-            let chunk = unsafe { &mut *VM.chunk };
-            let diff = unsafe { VM.instruction_pointer.offset_from(chunk.code) };
-            unsafe {
-                chunk.disassemble_instruction(diff);
+            #[cfg(feature = "debug_trace_execution")]
+            {
+                print!("          ");
+                for slot in 0..self.stack_top {
+                    print!("[ {} ]", self.stack[slot]);
+                }
+                println!();
+
+                let frame = self.frame();
+                let chunk = unsafe { &(*(*frame.closure).function).chunk };
+                let diff = unsafe { frame.instruction_pointer.offset_from(chunk.code) };
+                unsafe {
+                    chunk.disassemble_instruction(diff);
+                }
             }
-        }
-        let instruction: OpCode = read_byte().into();
-        match instruction {
-            OpCode::Constant => {
-                let constant = read_constant();
-                push(constant);
+            let instruction: OpCode = self.read_byte().into();
+            match instruction {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(instruction);
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::NIL),
+                OpCode::True => self.push(Value::boolean(true)),
+                OpCode::False => self.push(Value::boolean(false)),
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::boolean(values_equal(a, b)));
+                }
+                OpCode::Negate => {
+                    let Ok(value) = TryInto::<f64>::try_into(self.peek(0)) else {
+                        return Err(self.runtime_error("Operand must be a number."));
+                    };
+                    self.pop();
+                    self.push(Value::number(-value));
+                }
+                OpCode::Greater => binary_op!(Value::boolean, >),
+                OpCode::Less => binary_op!(Value::boolean, <),
+                OpCode::Add => {
+                    if is_string(self.peek(0)) && is_string(self.peek(1)) {
+                        self.concatenate();
+                    } else if let (Ok(b), Ok(a)) = (
+                        TryInto::<f64>::try_into(self.peek(0)),
+                        TryInto::<f64>::try_into(self.peek(1)),
+                    ) {
+                        self.pop();
+                        self.pop();
+                        self.push(Value::number(a + b));
+                    } else {
+                        return Err(
+                            self.runtime_error("Operands must be two numbers or two strings.")
+                        );
+                    }
+                }
+                OpCode::Subtract => binary_op!(Value::number, -),
+                OpCode::Multiply => binary_op!(Value::number, *),
+                OpCode::Divide => binary_op!(Value::number, /),
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::boolean(is_falsey(value)));
+                }
+                OpCode::Print => println!("{}", self.pop()),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let slots = self.frame().slots;
+                    let value = self.stack[slots + slot];
+                    self.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    // assignment is an expression, so leave the value on the stack
+                    let slots = self.frame().slots;
+                    self.stack[slots + slot] = self.peek(0);
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_string(instruction);
+                    let globals = &raw mut self.globals;
+                    unsafe { (*globals).set(self, name, self.peek(0)) };
+                    self.pop();
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_string(instruction);
+                    let globals = &raw const self.globals;
+                    let Some(value) = (unsafe { (*globals).get(name) }) else {
+                        return Err(self
+                            .runtime_error(format!("Undefined variable '{}'.", unsafe {
+                                (*name).as_str()
+                            })));
+                    };
+                    self.push(value);
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_string(instruction);
+                    let globals = &raw mut self.globals;
+                    if unsafe { (*globals).set(self, name, self.peek(0)) } {
+                        // assignment doesn't implicitly declare a variable
+                        unsafe { (*globals).delete(name) };
+                        return Err(self
+                            .runtime_error(format!("Undefined variable '{}'.", unsafe {
+                                (*name).as_str()
+                            })));
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    let frame = self.frame();
+                    frame.instruction_pointer =
+                        unsafe { frame.instruction_pointer.add(offset as usize) };
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if is_falsey(self.peek(0)) {
+                        let frame = self.frame();
+                        frame.instruction_pointer =
+                            unsafe { frame.instruction_pointer.add(offset as usize) };
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    let frame = self.frame();
+                    frame.instruction_pointer =
+                        unsafe { frame.instruction_pointer.sub(offset as usize) };
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    self.call_value(self.peek(arg_count as isize), arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = as_function(self.read_constant(instruction));
+                    let closure = new_closure(self, function);
+                    self.push(Value::obj(closure as *mut Obj));
+
+                    for i in 0..unsafe { (*closure).upvalue_count } {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            let slots = self.frame().slots;
+                            let local = &raw mut self.stack[slots + index];
+                            self.capture_upvalue(local)
+                        } else {
+                            unsafe { *(*self.frame().closure).upvalues.add(index) }
+                        };
+                        unsafe { *(*closure).upvalues.add(i) = upvalue };
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = unsafe { *(*self.frame().closure).upvalues.add(slot) };
+                    self.push(unsafe { *(*upvalue).location });
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = unsafe { *(*self.frame().closure).upvalues.add(slot) };
+                    unsafe { *(*upvalue).location = self.peek(0) };
+                }
+                OpCode::CloseUpvalue => {
+                    let top = &raw mut self.stack[self.stack_top - 1];
+                    self.close_upvalues(top);
+                    self.pop();
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_string(instruction);
+                    let class = new_class(self, name);
+                    self.push(Value::obj(class as *mut Obj));
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    if !is_instance(self.peek(0)) {
+                        return Err(self.runtime_error("Only instances have properties."));
+                    }
+
+                    let instance = as_instance(self.peek(0));
+                    let name = self.read_string(instruction);
+                    if let Some(value) = unsafe { (*instance).fields.get(name) } {
+                        // replace the instance with the field's value
+                        self.pop();
+                        self.push(value);
+                    } else {
+                        self.bind_method(unsafe { (*instance).class }, name)?;
+                    }
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    if !is_instance(self.peek(1)) {
+                        return Err(self.runtime_error("Only instances have fields."));
+                    }
+
+                    let instance = as_instance(self.peek(1));
+                    let name = self.read_string(instruction);
+                    unsafe { (*instance).fields.set(self, name, self.peek(0)) };
+                    // leave the assigned value on the stack, in place of the instance
+                    let value = self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_string(instruction);
+                    let method = self.peek(0);
+                    let class = as_class(self.peek(1));
+                    unsafe { (*class).methods.set(self, name, method) };
+                    self.pop();
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let method = self.read_string(instruction);
+                    let arg_count = self.read_byte();
+                    self.invoke(method, arg_count)?;
+                }
+                OpCode::Inherit => {
+                    let superclass = self.peek(1);
+                    if !is_class(superclass) {
+                        return Err(self.runtime_error("Superclass must be a class."));
+                    }
+
+                    // copy down the inherited methods, so method lookups don't walk the hierarchy.
+                    // This runs before the subclass's own methods are added, so they override these.
+                    let subclass = as_class(self.peek(0));
+                    unsafe {
+                        (*as_class(superclass))
+                            .methods
+                            .add_all(self, &mut (*subclass).methods)
+                    };
+                    self.pop();
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_string(instruction);
+                    let superclass = as_class(self.pop());
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let method = self.read_string(instruction);
+                    let arg_count = self.read_byte();
+                    let superclass = as_class(self.pop());
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let slots = self.frame().slots;
+                    let first_slot = &raw mut self.stack[slots];
+                    self.close_upvalues(first_slot);
+                    self.frame_count -= 1;
+                    // discard the callee's slots, including the callee itself
+                    self.stack_top = slots;
+                    if self.frame_count == self.entry_frame {
+                        // the script this run started with returned
+                        return Ok(result);
+                    }
+
+                    self.push(result);
+                }
             }
-            OpCode::Nil => push(Value::Nil),
-            OpCode::True => push(Value::Bool(true)),
-            OpCode::False => push(Value::Bool(false)),
-            OpCode::Equal => {
-                let b = pop();
-                let a = pop();
-                push(Value::Bool(values_equal(a, b)));
+        }
+    }
+}
+
+fn is_falsey(value: Value) -> bool {
+    is_nil(value) || !TryFrom::try_from(value).unwrap_or(true)
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.init_string = null_mut();
+        let globals = &raw mut self.globals;
+        unsafe { (*globals).free_table(self) };
+        let strings = &raw mut self.strings;
+        unsafe { (*strings).free_table(self) };
+        self.roots.release_all();
+        free_objects(self);
+    }
+}
+
+/// Why a script didn't run to completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    /// The source didn't compile.
+    Compile { diagnostics: Vec<Diagnostic> },
+    /// The script failed while running. The vm's stack was unwound and the vm can be reused.
+    Runtime {
+        message: String,
+        /// calls that were active when the error happened, innermost first
+        trace: Vec<TraceFrame>,
+    },
+}
+
+/// A call that was active when a runtime error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// None for the top level script
+    pub function: Option<String>,
+    /// line of the instruction that was executing, or of the call into the next frame
+    pub line: usize,
+    /// 1 based, counted in characters
+    pub column: usize,
+    /// the code that was executing, as a range of bytes in the source of the script
+    pub span: Span,
+    /// text of the line `span` starts on
+    pub source_line: String,
+}
+
+impl LoxError {
+    /// Format the error for a terminal, quoting the code that caused it.
+    ///
+    /// `source` must be the source that was compiled. Runtime errors quote the code from the
+    /// trace instead, since the failing function may come from an earlier script.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            LoxError::Compile { diagnostics } => diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.render(source))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            LoxError::Runtime { message, trace } => {
+                let mut rendered = message.clone();
+                if let Some(innermost) = trace.first() {
+                    rendered.push('\n');
+                    rendered.push_str(&innermost.quote());
+                }
+                for frame in trace {
+                    rendered.push_str(&format!("\n{frame}"));
+                }
+                rendered
             }
-            OpCode::Negate => {
-                let Ok(value) = TryInto::<f64>::try_into(peek(0)) else {
-                    runtime_error("Operand must be a number.");
-                    return Err(InterpretError::RuntimeError);
-                };
-                pop();
-                push(Value::Double(-value));
+        }
+    }
+}
+
+impl TraceFrame {
+    /// The source line with the code that was executing underlined.
+    pub fn quote(&self) -> String {
+        quote(
+            &self.source_line,
+            self.line,
+            self.column,
+            self.span.end - self.span.start,
+        )
+    }
+}
+
+/// Displays the error the way clox reports it, the message followed by the trace.
+impl Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoxError::Compile { diagnostics } => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
             }
-            OpCode::Greater => binary_op!(Value::Bool, >),
-            OpCode::Less => binary_op!(Value::Bool, <),
-            OpCode::Add => binary_op!(Value::Double, +),
-            OpCode::Subtract => binary_op!(Value::Double, -),
-            OpCode::Multiply => binary_op!(Value::Double, *),
-            OpCode::Divide => binary_op!(Value::Double, /),
-            OpCode::Not => push(Value::Bool(is_falsey(pop()))),
-            OpCode::Return => {
-                println!("{}", pop());
-                return Ok(());
+            LoxError::Runtime { message, trace } => {
+                write!(f, "{message}")?;
+                for frame in trace {
+                    write!(f, "\n{frame}")?;
+                }
+                Ok(())
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, LoxError, STACK_INITIAL, Vm};
+    use crate::object::copy_string;
+
+    fn global(vm: &mut Vm, name: &str) -> Option<f64> {
+        let name = copy_string(vm, name);
+        vm.globals
+            .get(name)
+            .map(|value| f64::try_from(value).unwrap())
+    }
+
+    #[test]
+    fn vms_are_independent() {
+        let mut a = Vm::new();
+        let mut b = Vm::new();
+        a.interpret("var x = 1;").unwrap();
+        b.interpret("var x = 2; var y = x + 1;").unwrap();
+
+        assert_eq!(global(&mut a, "x"), Some(1.0));
+        assert_eq!(global(&mut a, "y"), None);
+        assert_eq!(global(&mut b, "x"), Some(2.0));
+        assert_eq!(global(&mut b, "y"), Some(3.0));
+        assert!(a.globals.get(copy_string(&mut b, "x")).is_none());
+    }
+
+    #[test]
+    fn growing_the_stack_keeps_upvalues_open() {
+        let mut vm = Vm::with_limits(Limits {
+            max_stack: 100_000,
+            max_frames: 1_000,
+        });
+        let result = vm
+            .interpret(
+                "fun outer() {
+                    var x = 1;
+                    fun deep(n) {
+                        if (n > 0) return deep(n - 1);
+                        x = 2;
+                    }
+                    deep(500);
+                    return x;
+                }
+                var result = outer();",
+            )
+            .unwrap();
+        assert!(result.is_nil());
+        assert!(vm.stack.len() > STACK_INITIAL);
+        assert_eq!(global(&mut vm, "result"), Some(2.0));
+    }
+
+    #[test]
+    fn runtime_errors_close_upvalues() {
+        let mut vm = Vm::with_limits(Limits {
+            max_stack: 100_000,
+            max_frames: 1_000,
+        });
+        let err = vm
+            .interpret(
+                "var g;
+                {
+                    var x = \"captured\";
+                    fun f() { return x; }
+                    g = f;
+                    nil + 1;
+                }",
+            )
+            .unwrap_err();
+        assert!(matches!(err, LoxError::Runtime { .. }));
+
+        // reuse the slots x lived in, and move the stack
+        vm.interpret(
+            "fun deep(n) { var a = n; var b = n; if (n > 0) deep(n - 1); }
+            deep(500);",
+        )
+        .unwrap();
+        assert!(vm.stack.len() > STACK_INITIAL);
+        assert_eq!(vm.interpret("g();").unwrap().to_string(), "captured");
+    }
+
+    #[test]
+    fn limits_are_configurable() {
+        let source = "fun deep(n) { if (n > 0) deep(n - 1); } deep(20);";
+        let overflow = |err: LoxError| match err {
+            LoxError::Runtime { message, .. } => message == "Stack overflow.",
+            LoxError::Compile { .. } => false,
+        };
+
+        let mut vm = Vm::with_limits(Limits {
+            max_frames: 16,
+            ..Limits::default()
+        });
+        assert!(overflow(vm.interpret(source).unwrap_err()));
+
+        let mut vm = Vm::with_limits(Limits {
+            max_stack: 16,
+            ..Limits::default()
+        });
+        assert!(overflow(vm.interpret(source).unwrap_err()));
+
+        // locals count against the limit too, not just the slots at each call
+        let locals = (0..20)
+            .map(|i| format!("var a{i} = {i};"))
+            .collect::<String>();
+        assert!(overflow(
+            vm.interpret(&format!("{{ {locals} }}")).unwrap_err()
+        ));
+
+        // the vm is still usable after unwinding
+        assert!(vm.interpret("deep(2);").is_ok());
+        assert!(Vm::new().interpret(source).is_ok());
+    }
 }
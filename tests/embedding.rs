@@ -0,0 +1,267 @@
+//! Exercises the library API the way a host application would.
+
+use old_iron_lox::{Diagnostic, LoxError, Severity, Span, TraceFrame, Value, Vm};
+
+fn add(_vm: &mut Vm, args: &[Value]) -> Result<Value, String> {
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(a), Some(b)) => Ok(Value::number(a + b)),
+        _ => Err("Arguments must be numbers.".to_string()),
+    }
+}
+
+#[test]
+fn script_evaluates_to_its_last_expression() {
+    let mut vm = Vm::new();
+    assert_eq!(vm.interpret("1 + 2;").unwrap().as_number(), Some(3.0));
+    assert!(vm.interpret("var a = 1;").unwrap().is_nil());
+    assert!(vm.interpret("if (true) 1; else 2;").unwrap().is_nil());
+    assert!(vm.interpret("\"a\" + \"b\";").unwrap().is_string());
+}
+
+#[test]
+fn strings_are_copied_out_to_the_host() {
+    let mut vm = Vm::new();
+    let joined = vm.interpret("\"hé\" + \"llo\";").unwrap();
+    let contents = joined.as_string();
+    drop(joined);
+    drop(vm);
+    assert_eq!(contents.as_deref(), Some("héllo"));
+    assert_eq!(Value::number(1.0).as_string(), None);
+    assert_eq!(Value::NIL.as_string(), None);
+}
+
+#[test]
+fn globals_are_shared_with_the_host() {
+    let mut vm = Vm::new();
+    assert!(vm.get_global("x").is_none());
+
+    vm.set_global("x", Value::number(2.0));
+    vm.interpret("var y = x * 10;").unwrap();
+    assert_eq!(vm.get_global("y").unwrap().as_number(), Some(20.0));
+
+    let greeting = vm.new_string("hi");
+    vm.set_global("greeting", greeting);
+    let result = vm.interpret("greeting == \"hi\";").unwrap();
+    assert_eq!(result.as_bool(), Some(true));
+}
+
+#[test]
+fn host_values_keep_objects_alive() {
+    let mut vm = Vm::new();
+    let greeting = vm.new_string("hi");
+    let joined = vm.interpret("\"a\" + \"b\";").unwrap();
+    // allocate enough garbage to trigger several collections
+    vm.interpret(
+        "var s = \"\";
+        for (var i = 0; i < 1000; i = i + 1) { s = s + \"abcdefghijklmnopqrstuvwxyz\"; }",
+    )
+    .unwrap();
+    assert_eq!(greeting.to_string(), "hi");
+    assert_eq!(joined.to_string(), "ab");
+
+    vm.set_global("greeting", greeting.clone());
+    drop(greeting);
+    vm.set_global("joined", joined);
+    let result = vm.interpret("greeting + joined;").unwrap();
+    assert_eq!(result.to_string(), "hiab");
+}
+
+#[test]
+#[should_panic(expected = "value belongs to a different vm")]
+fn values_stay_with_their_vm() {
+    let mut a = Vm::new();
+    let mut b = Vm::new();
+    let string = a.new_string("a");
+    b.set_global("string", string);
+}
+
+#[test]
+#[should_panic(expected = "value outlived its vm")]
+fn values_cant_outlive_their_vm() {
+    let string = Vm::new().new_string("a");
+    string.is_string();
+}
+
+#[test]
+#[should_panic(expected = "script was compiled by a different vm")]
+fn scripts_stay_with_their_vm() {
+    let script = Vm::new().compile("1;").unwrap();
+    // even if the new vm is allocated where the old one was
+    Vm::new().execute(&script).unwrap();
+}
+
+#[test]
+fn natives_can_be_registered() {
+    let mut vm = Vm::new();
+    vm.define_native("add", add);
+    assert_eq!(vm.interpret("add(1, 2);").unwrap().as_number(), Some(3.0));
+
+    let err = vm.interpret("add(1, nil);").unwrap_err();
+    assert_eq!(
+        err,
+        LoxError::Runtime {
+            message: "Arguments must be numbers.".to_string(),
+            trace: vec![TraceFrame {
+                function: None,
+                line: 1,
+                column: 4,
+                span: Span { start: 3, end: 11 },
+                source_line: "add(1, nil);".to_string(),
+            }],
+        }
+    );
+}
+
+fn call_inner(vm: &mut Vm, _args: &[Value]) -> Result<Value, String> {
+    vm.interpret("inner();").map_err(|err| err.to_string())
+}
+
+#[test]
+fn natives_can_run_scripts() {
+    let mut vm = Vm::new();
+    vm.define_native("callInner", call_inner);
+    let result = vm
+        .interpret(
+            "var calls = 0;
+            fun inner() { calls = calls + 1; return calls * 10; }
+            fun outer() { var before = 1; return before + callInner(); }
+            outer() + outer();",
+        )
+        .unwrap();
+    assert_eq!(result.as_number(), Some(32.0));
+
+    // the inner error fails the native, the outer script reports it with its own frames
+    vm.interpret("fun inner() { return nil + 1; }").unwrap();
+    let LoxError::Runtime { message, trace } = vm.interpret("outer();").unwrap_err() else {
+        panic!("expected a runtime error");
+    };
+    assert!(message.starts_with("Operands must be two numbers or two strings."));
+    let functions: Vec<_> = trace
+        .iter()
+        .map(|frame| frame.function.as_deref())
+        .collect();
+    assert_eq!(functions, [Some("outer"), None]);
+    assert_eq!(vm.interpret("calls;").unwrap().as_number(), Some(2.0));
+}
+
+#[test]
+fn compiled_scripts_can_run_repeatedly() {
+    let mut vm = Vm::new();
+    let script = vm.compile("n = n + 1;").unwrap();
+    // collect garbage between runs by allocating plenty of strings
+    vm.interpret("for (var i = 0; i < 10000; i = i + 1) { var s = \"a\" + \"b\"; }")
+        .unwrap();
+    vm.set_global("n", Value::number(0.0));
+    for expected in 1..=3 {
+        let result = vm.execute(&script).unwrap();
+        assert_eq!(result.as_number(), Some(f64::from(expected)));
+    }
+}
+
+#[test]
+fn errors_leave_the_vm_usable() {
+    let mut vm = Vm::new();
+    assert!(matches!(
+        vm.interpret("var;").unwrap_err(),
+        LoxError::Compile { .. }
+    ));
+    assert!(vm.compile("print").is_err());
+
+    let err = vm.interpret("var a = 1;\n-nil;").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Operand must be a number.\n[line 2] in script"
+    );
+
+    assert_eq!(vm.interpret("a + 1;").unwrap().as_number(), Some(2.0));
+}
+
+#[test]
+fn runtime_errors_carry_a_trace() {
+    let mut vm = Vm::new();
+    let err = vm
+        .interpret(
+            "fun a() { b(); }\n\
+             fun b() {\n\
+               c();\n\
+             }\n\
+             fun c() { return 1 + nil; }\n\
+             a();",
+        )
+        .unwrap_err();
+    let LoxError::Runtime { message, trace } = &err else {
+        panic!("expected a runtime error, got {err:?}");
+    };
+    assert_eq!(message, "Operands must be two numbers or two strings.");
+    let frames: Vec<_> = trace
+        .iter()
+        .map(|frame| (frame.function.as_deref(), frame.line))
+        .collect();
+    assert_eq!(
+        frames,
+        [(Some("c"), 5), (Some("b"), 3), (Some("a"), 1), (None, 6)]
+    );
+    assert_eq!(
+        err.to_string(),
+        "Operands must be two numbers or two strings.\n\
+         [line 5] in c()\n\
+         [line 3] in b()\n\
+         [line 1] in a()\n\
+         [line 6] in script"
+    );
+}
+
+#[test]
+fn compile_errors_are_returned_as_diagnostics() {
+    let mut vm = Vm::new();
+    let source = "var a = 1;\nprint a b;";
+    let LoxError::Compile { diagnostics } = vm.interpret(source).unwrap_err() else {
+        panic!("expected a compile error");
+    };
+    assert_eq!(
+        diagnostics,
+        [Diagnostic {
+            severity: Severity::Error,
+            message: "Expect ';' after value.".to_string(),
+            span: Span { start: 19, end: 20 },
+            line: 2,
+            column: 9,
+            notes: Vec::new(),
+        }]
+    );
+    assert_eq!(
+        diagnostics[0].render(source),
+        "[line 2] Error at 'b': Expect ';' after value.\n\
+         2 | print a b;\n  \
+           |         ^"
+    );
+}
+
+#[test]
+fn runtime_errors_quote_the_failing_code() {
+    let mut vm = Vm::new();
+    let source = "var a = 1;\nvar b = a + 2 * (a < nil);";
+    let err = vm.interpret(source).unwrap_err();
+    assert_eq!(
+        err.render(source),
+        "Operands must be a numbers.\n\
+         2 | var b = a + 2 * (a < nil);\n  \
+           |                    ^\n\
+         [line 2] in script"
+    );
+    // instructions spread over several lines are blamed on the line their span starts on
+    let source = "var a = 1 +\n\n  nil;";
+    assert_eq!(
+        vm.interpret(source).unwrap_err().render(source),
+        "Operands must be two numbers or two strings.\n\
+         1 | var a = 1 +\n  \
+           |           ^\n\
+         [line 1] in script"
+    );
+    let source = "len(\n  1\n);";
+    let LoxError::Runtime { trace, .. } = vm.interpret(source).unwrap_err() else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!((trace[0].line, trace[0].column), (1, 4));
+    assert_eq!(trace[0].source_line, "len(");
+}
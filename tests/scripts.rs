@@ -0,0 +1,136 @@
+//! Runs every `.lox` script in this directory through the interpreter and checks its output
+//! against the comments in the script:
+//!
+//! - `// expect: <line>` the next line printed to stdout
+//! - `// expect runtime error: <message>` the script stops with a runtime error
+//! - `// expect compile error: <message>` the script fails to compile, reporting this error. A
+//!   script can expect several, in the order they are reported
+//!
+//! Scripts without any expectations are scratch files rather than tests, and are skipped.
+
+use std::{fs, path::Path, process::Command};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const EXPECT_COMPILE_ERROR: &str = "// expect compile error: ";
+
+struct Expectations {
+    output: Vec<String>,
+    runtime_error: Option<String>,
+    compile_errors: Vec<String>,
+}
+
+fn parse_expectations(source: &str) -> Expectations {
+    let mut expectations = Expectations {
+        output: Vec::new(),
+        runtime_error: None,
+        compile_errors: Vec::new(),
+    };
+    for line in source.lines() {
+        if let Some((_, expected)) = line.split_once(EXPECT) {
+            expectations.output.push(expected.to_string());
+        } else if let Some((_, message)) = line.split_once(EXPECT_RUNTIME_ERROR) {
+            expectations.runtime_error = Some(message.to_string());
+        } else if let Some((_, message)) = line.split_once(EXPECT_COMPILE_ERROR) {
+            expectations.compile_errors.push(message.to_string());
+        }
+    }
+    expectations
+}
+
+impl Expectations {
+    fn is_empty(&self) -> bool {
+        self.output.is_empty() && self.runtime_error.is_none() && self.compile_errors.is_empty()
+    }
+}
+
+fn run_script(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let expectations = parse_expectations(&source);
+    if expectations.is_empty() {
+        return Ok(());
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_old_iron_lox"))
+        .arg(path)
+        .output()
+        .map_err(|err| err.to_string())?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // the first line is the "running <path>" banner
+    let mut lines = stdout.lines().skip(1);
+
+    if !expectations.compile_errors.is_empty() {
+        if output.status.code() != Some(65) {
+            return Err(format!("expected compile error, got {}", output.status));
+        }
+        // every error is reported on a line starting with "[line N] Error"
+        let reported: Vec<_> = stderr
+            .lines()
+            .filter(|line| line.starts_with("[line "))
+            .collect();
+        if reported.len() != expectations.compile_errors.len() {
+            return Err(format!(
+                "expected {} compile errors, got '{stderr}'",
+                expectations.compile_errors.len()
+            ));
+        }
+        for (message, reported) in expectations.compile_errors.iter().zip(reported) {
+            if !reported.contains(message.as_str()) {
+                return Err(format!(
+                    "expected compile error '{message}', got '{reported}'"
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    for expected in &expectations.output {
+        match lines.next() {
+            Some(actual) if actual == expected => {}
+            actual => return Err(format!("expected '{expected}', got {actual:?}")),
+        }
+    }
+
+    if let Some(message) = &expectations.runtime_error {
+        if output.status.code() != Some(70) {
+            return Err(format!("expected runtime error, got {}", output.status));
+        }
+        if !stderr.contains(message.as_str()) {
+            return Err(format!(
+                "expected runtime error '{message}', got '{stderr}'"
+            ));
+        }
+        return Ok(());
+    }
+
+    if let Some(extra) = lines.next() {
+        return Err(format!("unexpected output '{extra}'"));
+    }
+    if !output.status.success() {
+        return Err(format!("exited with {}: {stderr}", output.status));
+    }
+    Ok(())
+}
+
+#[test]
+fn scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let failures: Vec<_> = paths
+        .iter()
+        .filter_map(|path| {
+            run_script(path)
+                .err()
+                .map(|err| format!("{}: {err}", path.display()))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}